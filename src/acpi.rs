@@ -0,0 +1,109 @@
+use crate::error::Error;
+
+pub const SYSFS_ACPI_TABLES: &str = "/sys/firmware/acpi/tables";
+
+pub const ACPI_FADT_SIGNATURE: &str = "FACP";
+pub const ACPI_FADT_BOOT_FLAGS: usize = 109;
+pub const ACPI_FADT_NO_ASPM: u16 = 0x0010;
+
+pub fn fadt_path(acpi_tables: &str) -> String {
+    std::path::Path::new(acpi_tables)
+        .join(ACPI_FADT_SIGNATURE)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// IA-PC Boot Architecture Flags of the FADT under `acpi_tables`, `None` without ACPI.
+/// Tables too short to hold the flags (ACPI 1.0) report none set, as the kernel does.
+pub fn read_fadt_boot_flags(acpi_tables: &str) -> Result<Option<u16>, Error> {
+    let path = fadt_path(acpi_tables);
+
+    let table = match std::fs::read(&path) {
+        Ok(table) => table,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::new("read", &path, err)),
+    };
+
+    if !table.starts_with(ACPI_FADT_SIGNATURE.as_bytes()) {
+        return Err(Error::new("parse", &path, "not a fadt"));
+    }
+
+    let Some(boot_flags) = table.get(ACPI_FADT_BOOT_FLAGS..(ACPI_FADT_BOOT_FLAGS + 2)) else {
+        return Ok(Some(0));
+    };
+
+    Ok(Some(u16::from_le_bytes([boot_flags[0], boot_flags[1]])))
+}
+
+/// Whether the firmware tells the OS not to enable ASPM ("PCIe ASPM Controls").
+pub fn firmware_forbids_aspm(acpi_tables: &str) -> Result<bool, Error> {
+    Ok(read_fadt_boot_flags(acpi_tables)?
+        .is_some_and(|boot_flags| boot_flags & ACPI_FADT_NO_ASPM != 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_acpi_tables(name: &str, fadt: Option<&[u8]>) -> String {
+        let directory = std::env::temp_dir()
+            .join(format!("aspmctl-test-{}", std::process::id()))
+            .join(name);
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        if let Some(fadt) = fadt {
+            std::fs::write(directory.join(ACPI_FADT_SIGNATURE), fadt).unwrap();
+        }
+
+        directory.to_string_lossy().into_owned()
+    }
+
+    fn fadt(length: usize, boot_flags: u16) -> Vec<u8> {
+        let mut table = vec![0u8; length];
+        table[..4].copy_from_slice(ACPI_FADT_SIGNATURE.as_bytes());
+
+        if let Some(flags) = table.get_mut(ACPI_FADT_BOOT_FLAGS..(ACPI_FADT_BOOT_FLAGS + 2)) {
+            flags.copy_from_slice(&boot_flags.to_le_bytes());
+        }
+
+        table
+    }
+
+    #[test]
+    fn read_fadt_boot_flags_without_acpi() {
+        let acpi_tables = temporary_acpi_tables("acpi-missing", None);
+
+        assert_eq!(read_fadt_boot_flags(&acpi_tables).unwrap(), None);
+        assert!(!firmware_forbids_aspm(&acpi_tables).unwrap());
+    }
+
+    #[test]
+    fn read_fadt_boot_flags_of_short_table() {
+        let acpi_tables = temporary_acpi_tables("acpi-short", Some(&fadt(74, 0)));
+
+        assert_eq!(read_fadt_boot_flags(&acpi_tables).unwrap(), Some(0));
+        assert!(!firmware_forbids_aspm(&acpi_tables).unwrap());
+    }
+
+    #[test]
+    fn read_fadt_boot_flags_no_aspm() {
+        let acpi_tables = temporary_acpi_tables("acpi-no-aspm", Some(&fadt(276, 0x0013)));
+
+        assert_eq!(read_fadt_boot_flags(&acpi_tables).unwrap(), Some(0x0013));
+        assert!(firmware_forbids_aspm(&acpi_tables).unwrap());
+
+        let acpi_tables = temporary_acpi_tables("acpi-aspm", Some(&fadt(276, 0x0003)));
+
+        assert!(!firmware_forbids_aspm(&acpi_tables).unwrap());
+    }
+
+    #[test]
+    fn read_fadt_boot_flags_rejects_other_tables() {
+        let mut table = fadt(276, 0);
+        table[..4].copy_from_slice(b"APIC");
+        let acpi_tables = temporary_acpi_tables("acpi-bad-signature", Some(&table));
+
+        assert!(read_fadt_boot_flags(&acpi_tables).is_err());
+    }
+}
@@ -0,0 +1,722 @@
+use crate::acpi::{fadt_path, firmware_forbids_aspm, SYSFS_ACPI_TABLES};
+use crate::aspm::{
+    apply_aspm_link, aspm_latency_violations, configure_common_clock, has_common_clock, plan_aspm,
+    plan_l12_timing, unsupported_aspm_names, verify_link_control, AspmPlan, AspmRequest,
+    LatencyViolation,
+};
+use crate::capability::is_pcie_downstream_port;
+use crate::config::{read_config_u16, PciConfig};
+use crate::error::Error;
+use crate::ltr::{apply_ltr_hierarchy, plan_ltr_hierarchy, LtrPlan, LtrRequest};
+use crate::policy::{aspm_policy_enables, read_aspm_policy, SYSFS_PCIE_ASPM_POLICY};
+use crate::regs::*;
+use crate::source::ConfigSource;
+use crate::state::{save_state, DEFAULT_STATE_FILE};
+use crate::sysfs::{plan_sysfs_link, sysfs_link_path, write_sysfs_link};
+
+/// How ASPM settings reach the hardware.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The kernel's per-link controls where they can make the change and the kernel policy
+    /// keeps it, raw writes otherwise.
+    #[default]
+    Auto,
+    SysfsLink,
+    Raw,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::SysfsLink => "sysfs-link",
+            Backend::Raw => "raw",
+        }
+    }
+}
+
+/// Everything `apply_link` needs to know besides the link itself.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    pub request: AspmRequest,
+    pub ltr: LtrRequest,
+    pub program_l12_timing: bool,
+    pub common_clock: bool,
+    pub dry_run: bool,
+    /// Apply settings the link capabilities or exit latencies rule out, with a warning.
+    pub force: bool,
+    /// Enable ASPM although the FADT forbids it, with a warning.
+    pub ignore_firmware: bool,
+    pub backend: Backend,
+    pub state_file: String,
+    pub acpi_tables: String,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> ApplyOptions {
+        ApplyOptions {
+            request: AspmRequest::default(),
+            ltr: LtrRequest::default(),
+            program_l12_timing: false,
+            common_clock: false,
+            dry_run: false,
+            force: false,
+            ignore_firmware: false,
+            backend: Backend::Auto,
+            state_file: DEFAULT_STATE_FILE.to_string(),
+            acpi_tables: SYSFS_ACPI_TABLES.to_string(),
+        }
+    }
+}
+
+/// How `execute_aspm_plans` carried out a set of plans.
+#[derive(Debug)]
+pub struct AspmExecution {
+    /// `Backend::SysfsLink` or `Backend::Raw`.
+    pub backend: Backend,
+    /// Attribute writes, when the kernel link controls make the change.
+    pub sysfs_writes: Option<Vec<(String, bool)>>,
+    pub dry_run: bool,
+    /// Whether every write went through and the registers were read back.
+    pub completed: bool,
+    pub sysfs_written: bool,
+}
+
+/// Receives what `apply_link` plans and does, in order, so the caller can show it.
+pub trait ApplyReporter {
+    fn warning(&mut self, message: String);
+
+    /// LTR plans for the functions of `hierarchy`, after they were written or, on a dry run,
+    /// instead of writing them.
+    fn ltr_plans(&mut self, hierarchy: &[PciConfig], plans: &[LtrPlan], dry_run: bool);
+
+    /// ASPM plans for the functions of `link`, after `execute_aspm_plans` carried them out.
+    fn aspm_plans(&mut self, link: &[PciConfig], plans: &[AspmPlan], execution: &AspmExecution);
+}
+
+fn firmware_aspm_override(options: &ApplyOptions, source: &ConfigSource) -> Result<bool, Error> {
+    let aspm_enables = (options.request.mask & options.request.flags & PCI_EXP_LNKCTL_ASPMC) != 0
+        || (options.request.l1ss_mask
+            & options.request.l1ss_flags
+            & (PCI_L1SS_CTL1_ASPM_L1_1 | PCI_L1SS_CTL1_ASPM_L1_2))
+            != 0;
+
+    // The FADT of this machine is no reason to refuse planning for another.
+    if !aspm_enables || source.is_offline() {
+        return Ok(false);
+    }
+
+    if !firmware_forbids_aspm(&options.acpi_tables)? {
+        return Ok(false);
+    }
+
+    if !options.ignore_firmware {
+        return Err(Error::new(
+            "error",
+            &fadt_path(&options.acpi_tables),
+            "fadt forbids the os from enabling aspm (use --ignore-firmware to override)",
+        ));
+    }
+
+    Ok(true)
+}
+
+/// Refuses ASPM enables when the FADT tells the OS to leave ASPM to the firmware.
+/// `apply_link` checks again, so this only gets the refusal or warning out once up front.
+pub fn check_firmware_aspm(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    reporter: &mut dyn ApplyReporter,
+) -> Result<(), Error> {
+    if firmware_aspm_override(options, source)? {
+        reporter.warning(format!(
+            "{}: enabling aspm although the fadt forbids it",
+            fadt_path(&options.acpi_tables)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether `force` overrode the check.
+fn check_aspm_support(
+    options: &ApplyOptions,
+    config: &PciConfig,
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    let unsupported_names = unsupported_aspm_names(&options.request, config);
+
+    if unsupported_names.is_empty() {
+        return Ok(false);
+    }
+
+    if !options.force {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            format!(
+                "{} not supported (use --force to override)",
+                unsupported_names.join(", ")
+            ),
+        ));
+    }
+
+    reporter.warning(format!(
+        "{}: forcing {} not supported",
+        config.path,
+        unsupported_names.join(", ")
+    ));
+
+    Ok(true)
+}
+
+fn requested_latency_violations(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    config: &PciConfig,
+) -> Result<Vec<LatencyViolation>, Error> {
+    let aspm_control = options.request.flags & PCI_EXP_LNKCTL_ASPMC;
+
+    if aspm_control == 0 || is_pcie_downstream_port(&config.buffer) {
+        return Ok(Vec::new());
+    }
+
+    aspm_latency_violations(
+        aspm_control,
+        config,
+        &source.open_path_to_root(&config.path)?,
+    )
+}
+
+fn latency_violation_reason(violations: &[LatencyViolation]) -> String {
+    violations
+        .iter()
+        .map(|violation| violation.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns whether `force` overrode the check.
+fn check_aspm_latency(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    config: &PciConfig,
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    let violations = requested_latency_violations(options, source, config)?;
+
+    if violations.is_empty() {
+        return Ok(false);
+    }
+
+    if !options.force {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            format!(
+                "{} (use --force to override)",
+                latency_violation_reason(&violations)
+            ),
+        ));
+    }
+
+    reporter.warning(format!(
+        "{}: forcing {}",
+        config.path,
+        latency_violation_reason(&violations)
+    ));
+
+    Ok(true)
+}
+
+/// Why `apply_link` would refuse `link` without `force`, for callers that skip such links.
+pub fn link_skip_reason(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &[PciConfig],
+) -> Option<String> {
+    link.iter().find_map(|config| {
+        let unsupported_names = unsupported_aspm_names(&options.request, config);
+
+        if !unsupported_names.is_empty() {
+            return Some(format!("{} not supported", unsupported_names.join(", ")));
+        }
+
+        // Errors surface again from apply_link.
+        let violations = requested_latency_violations(options, source, config).unwrap_or_default();
+
+        if violations.is_empty() {
+            None
+        } else {
+            Some(latency_violation_reason(&violations))
+        }
+    })
+}
+
+fn save_link_state(
+    options: &ApplyOptions,
+    configs: &[PciConfig],
+    reporter: &mut dyn ApplyReporter,
+) -> Result<(), Error> {
+    for path in save_state(&options.state_file, configs)? {
+        reporter.warning(format!(
+            "{}: not a pci device address, original state not saved",
+            path
+        ));
+    }
+
+    Ok(())
+}
+
+fn apply_ltr(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &[PciConfig],
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    let mut changed = false;
+
+    if options.ltr == LtrRequest::default() {
+        return Ok(changed);
+    }
+
+    for config in link
+        .iter()
+        .filter(|config| link.len() == 1 || !is_pcie_downstream_port(&config.buffer))
+    {
+        let mut hierarchy = source.open_hierarchy(&config.path, !options.dry_run)?;
+        let plans = plan_ltr_hierarchy(&options.ltr, &hierarchy)?;
+
+        // Enabling LTR on the device enables it on every port above, so save those as well.
+        let result = if options.dry_run {
+            Ok(())
+        } else {
+            save_link_state(options, &hierarchy, reporter)
+                .and_then(|()| apply_ltr_hierarchy(&mut hierarchy, &plans))
+        };
+
+        reporter.ltr_plans(&hierarchy, &plans, options.dry_run);
+
+        result?;
+
+        changed |= plans.iter().any(ltr_plan_changes);
+    }
+
+    Ok(changed)
+}
+
+fn ltr_plan_changes(plan: &LtrPlan) -> bool {
+    plan.device_control2_new_value != plan.device_control2_old_value
+        || plan.max_snoop_latency_new_value != plan.max_snoop_latency_old_value
+        || plan.max_no_snoop_latency_new_value != plan.max_no_snoop_latency_old_value
+}
+
+/// Applies `options` to `link`, the downstream port followed by the functions below it, or a
+/// lone function. Checks support, exit latencies and the firmware, saves the original values,
+/// writes in the order the spec requires and verifies the result. Returns whether anything
+/// changed, or would change on a dry run.
+pub fn apply_link(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &mut [PciConfig],
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    let mut forced = firmware_aspm_override(options, source)?;
+
+    let mut plans = link
+        .iter()
+        .map(|config| {
+            forced |= check_aspm_support(options, config, reporter)?;
+            forced |= check_aspm_latency(options, source, config, reporter)?;
+            plan_aspm(&options.request, config)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    if options.program_l12_timing {
+        plan_l12_timing(link, &mut plans)?;
+    }
+
+    if options.common_clock {
+        if has_common_clock(link)? {
+            for plan in plans.iter_mut() {
+                plan.link_control_new_value |= PCI_EXP_LNKCTL_CCC;
+            }
+        } else {
+            reporter.warning(format!(
+                "{}: no common reference clock, leaving common clock configuration unchanged",
+                link[0].path
+            ));
+        }
+    }
+
+    if !options.dry_run {
+        save_link_state(options, link, reporter)?;
+
+        if options.common_clock {
+            configure_common_clock(link, &plans)?;
+        }
+    }
+
+    // L1.2 entry depends on LTR, so LTR comes up before ASPM and goes down after it.
+    if options.ltr.enable == Some(false) {
+        let changed = execute_aspm_plans(options, source, link, &plans, forced, reporter)?;
+
+        Ok(apply_ltr(options, source, link, reporter)? || changed)
+    } else {
+        let changed = apply_ltr(options, source, link, reporter)?;
+
+        Ok(execute_aspm_plans(options, source, link, &plans, forced, reporter)? || changed)
+    }
+}
+
+fn sysfs_link_writes(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &[PciConfig],
+    plans: &[AspmPlan],
+    forced: bool,
+) -> Result<Option<Vec<(String, bool)>>, Error> {
+    if options.backend == Backend::Raw || source.is_offline() {
+        return Ok(None);
+    }
+
+    let config = &link[link.len() - 1];
+
+    // The kernel only enables the states both ends support and the firmware allows.
+    if forced {
+        if options.backend == Backend::SysfsLink {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "forced change not possible through kernel link controls (use --backend=raw)",
+            ));
+        }
+
+        return Ok(None);
+    }
+
+    let Some(link_path) = sysfs_link_path(&config.path) else {
+        if options.backend == Backend::SysfsLink {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "kernel link controls not available",
+            ));
+        }
+
+        return Ok(None);
+    };
+
+    let writes = plan_sysfs_link(&link_path, link, plans)?;
+    let target_plan = &plans[plans.len() - 1];
+
+    // Auto leaves enables to the attributes only where the kernel policy keeps them on.
+    if options.backend == Backend::Auto
+        && writes
+            .as_ref()
+            .is_some_and(|writes| writes.iter().any(|(_, enable)| *enable))
+        && !aspm_policy_enables(
+            read_aspm_policy(SYSFS_PCIE_ASPM_POLICY)?,
+            target_plan.link_control_new_value & (PCI_EXP_LNKCTL_ASPMC | PCI_EXP_LNKCTL_CLKREQ_EN),
+            target_plan.l1ss_control_new_value & PCI_L1SS_CTL1_L1SS_MASK,
+        )
+    {
+        return Ok(None);
+    }
+
+    if writes.is_none() && options.backend == Backend::SysfsLink {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            "change not possible through kernel link controls (use --backend=raw)",
+        ));
+    }
+
+    Ok(writes)
+}
+
+/// Writes `plans` through the chosen backend and verifies Link Control afterwards; a mismatch
+/// is an error with context "verify". `forced` plans, which got past a check only through
+/// `force` or `ignore_firmware`, take raw writes. Returns whether anything changed.
+pub fn execute_aspm_plans(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &mut [PciConfig],
+    plans: &[AspmPlan],
+    forced: bool,
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    let sysfs_writes = sysfs_link_writes(options, source, link, plans, forced)?;
+
+    let result = match &sysfs_writes {
+        _ if options.dry_run => Ok(()),
+        Some(writes) => write_sysfs_link(writes),
+        None => apply_aspm_link(link, plans),
+    };
+
+    let result = match result {
+        Ok(()) if !options.dry_run => verify_link_control(link, plans),
+        result => result.map(|()| Vec::new()),
+    };
+
+    let sysfs_written = !options.dry_run
+        && result.is_ok()
+        && sysfs_writes
+            .as_ref()
+            .is_some_and(|writes| !writes.is_empty());
+
+    let execution = AspmExecution {
+        backend: if sysfs_writes.is_some() {
+            Backend::SysfsLink
+        } else {
+            Backend::Raw
+        },
+        sysfs_writes,
+        dry_run: options.dry_run,
+        completed: !options.dry_run && result.is_ok(),
+        sysfs_written,
+    };
+
+    reporter.aspm_plans(link, plans, &execution);
+
+    let mismatches = result?;
+
+    if !mismatches.is_empty() {
+        return Err(Error::without_subject(
+            "verify",
+            mismatches
+                .iter()
+                .map(|mismatch| mismatch.to_string())
+                .collect::<Vec<_>>()
+                .join("; "),
+        ));
+    }
+
+    Ok(sysfs_written
+        || plans.iter().any(|plan| {
+            plan.link_control_new_value != plan.link_control_old_value
+                || plan.l1ss_control_new_value != plan.l1ss_control_old_value
+                || plan.l1ss_control2_new_value != plan.l1ss_control2_old_value
+        }))
+}
+
+/// Writes the values `plan_restore` read from the state file back to `link`, the downstream
+/// port followed by the functions below it, or a lone function. A restored Common Clock
+/// Configuration only takes effect after the downstream port retrains the link, so without
+/// the port the current setting stays. Returns whether anything changed.
+pub fn restore_link(
+    options: &ApplyOptions,
+    source: &ConfigSource,
+    link: &mut [PciConfig],
+    plans: &mut [AspmPlan],
+    reporter: &mut dyn ApplyReporter,
+) -> Result<bool, Error> {
+    if link.len() > 1 && is_pcie_downstream_port(&link[0].buffer) {
+        if !options.dry_run {
+            configure_common_clock(link, plans)?;
+        }
+    } else {
+        for (config, plan) in link.iter().zip(plans.iter_mut()) {
+            let link_control_value = read_config_u16(&config.buffer, plan.link_control_offset);
+
+            plan.link_control_new_value = (plan.link_control_new_value & !PCI_EXP_LNKCTL_CCC)
+                | (link_control_value & PCI_EXP_LNKCTL_CCC);
+        }
+    }
+
+    // Saved values were the kernel's or the firmware's, so no check needed forcing.
+    execute_aspm_plans(options, source, link, plans, false, reporter)
+}
+
+/// Writes back the LTR settings `plan_ltr_restore` planned for `functions`, listed from the root
+/// ports down, with the ordering `apply_ltr_hierarchy` keeps within a hierarchy. Returns which
+/// functions changed.
+pub fn restore_ltr(
+    options: &ApplyOptions,
+    functions: &mut [PciConfig],
+    plans: &[LtrPlan],
+    reporter: &mut dyn ApplyReporter,
+) -> Result<Vec<bool>, Error> {
+    let result = if options.dry_run {
+        Ok(())
+    } else {
+        apply_ltr_hierarchy(functions, plans)
+    };
+
+    reporter.ltr_plans(functions, plans, options.dry_run);
+
+    result?;
+
+    Ok(plans.iter().map(ltr_plan_changes).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::*;
+
+    #[derive(Debug, Default)]
+    struct TestReporter {
+        warnings: Vec<String>,
+        executions: usize,
+    }
+
+    impl ApplyReporter for TestReporter {
+        fn warning(&mut self, message: String) {
+            self.warnings.push(message);
+        }
+
+        fn ltr_plans(&mut self, _hierarchy: &[PciConfig], _plans: &[LtrPlan], _dry_run: bool) {}
+
+        fn aspm_plans(
+            &mut self,
+            _link: &[PciConfig],
+            _plans: &[AspmPlan],
+            execution: &AspmExecution,
+        ) {
+            assert_eq!(execution.backend, Backend::Raw);
+            self.executions += 1;
+        }
+    }
+
+    fn raw_options() -> ApplyOptions {
+        ApplyOptions {
+            backend: Backend::Raw,
+            state_file: std::env::temp_dir()
+                .join(format!("aspmctl-test-{}", std::process::id()))
+                .join("apply")
+                .to_string_lossy()
+                .into_owned(),
+            ..ApplyOptions::default()
+        }
+    }
+
+    fn disable_request(mask: u16) -> AspmRequest {
+        AspmRequest {
+            mask,
+            flags: 0,
+            ..AspmRequest::default()
+        }
+    }
+
+    fn link_control(config: &PciConfig) -> u16 {
+        read_config_u16(&config.buffer, TEST_LINK_CONTROL)
+    }
+
+    #[test]
+    fn apply_link_warns_when_state_cannot_be_saved() {
+        let log = WriteLog::default();
+        let options = ApplyOptions {
+            request: disable_request(PCI_EXP_LNKCTL_ASPMC),
+            ..raw_options()
+        };
+        let mut link = [TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_control(PCI_EXP_LNKCTL_ASPM_L1)
+            .open("ep", &log)];
+        let mut reporter = TestReporter::default();
+
+        assert!(apply_link(&options, &ConfigSource::Sysfs, &mut link, &mut reporter).unwrap());
+        assert_eq!(
+            reporter.warnings,
+            ["ep: not a pci device address, original state not saved"]
+        );
+        assert_eq!(link_control(&link[0]), 0);
+        assert!(!std::path::Path::new(&options.state_file).exists());
+    }
+
+    #[test]
+    fn execute_aspm_plans_fails_verification_on_hardwired_bits() {
+        let log = WriteLog::default();
+        let mut link = [TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_control(PCI_EXP_LNKCTL_ASPM_L1)
+            .hardwire_u16(TEST_LINK_CONTROL, PCI_EXP_LNKCTL_ASPM_L1)
+            .open("ep", &log)];
+        let plans = [plan_aspm(&disable_request(PCI_EXP_LNKCTL_ASPM_L1), &link[0]).unwrap()];
+        let mut reporter = TestReporter::default();
+
+        let err = execute_aspm_plans(
+            &raw_options(),
+            &ConfigSource::Sysfs,
+            &mut link,
+            &plans,
+            false,
+            &mut reporter,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.context, "verify");
+        assert_eq!(reporter.executions, 1);
+    }
+
+    #[test]
+    fn restore_link_keeps_common_clock_without_the_port() {
+        let log = WriteLog::default();
+        let mut link = [TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_control(PCI_EXP_LNKCTL_CCC)
+            .open("ep", &log)];
+        let mut plans = [plan_aspm(&disable_request(PCI_EXP_LNKCTL_CCC), &link[0]).unwrap()];
+        let mut reporter = TestReporter::default();
+
+        // The saved value had L1 on and Common Clock Configuration off.
+        plans[0].link_control_new_value = PCI_EXP_LNKCTL_ASPM_L1;
+
+        let changed = restore_link(
+            &raw_options(),
+            &ConfigSource::Sysfs,
+            &mut link,
+            &mut plans,
+            &mut reporter,
+        )
+        .unwrap();
+
+        assert!(changed);
+        assert_eq!(
+            link_control(&link[0]),
+            PCI_EXP_LNKCTL_CCC | PCI_EXP_LNKCTL_ASPM_L1
+        );
+        assert_eq!(reporter.executions, 1);
+    }
+
+    #[test]
+    fn restore_link_retrains_for_common_clock() {
+        let log = WriteLog::default();
+        let mut link = [
+            TestFunction::new(PCI_EXP_TYPE_ROOT_PORT)
+                .link_control(PCI_EXP_LNKCTL_CCC)
+                .hardwire_u16(TEST_LINK_CONTROL, PCI_EXP_LNKCTL_RL)
+                .open("rp", &log),
+            TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+                .link_control(PCI_EXP_LNKCTL_CCC)
+                .open("ep", &log),
+        ];
+        let mut plans = link
+            .iter()
+            .map(|config| plan_aspm(&disable_request(PCI_EXP_LNKCTL_CCC), config).unwrap())
+            .collect::<Vec<_>>();
+        let mut reporter = TestReporter::default();
+
+        let changed = restore_link(
+            &raw_options(),
+            &ConfigSource::Sysfs,
+            &mut link,
+            &mut plans,
+            &mut reporter,
+        )
+        .unwrap();
+
+        assert!(changed);
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("ep".to_string(), TEST_LINK_CONTROL, 0),
+                ("rp".to_string(), TEST_LINK_CONTROL, 0),
+                (
+                    "rp".to_string(),
+                    TEST_LINK_CONTROL,
+                    PCI_EXP_LNKCTL_RL as u32
+                ),
+            ]
+        );
+        assert!(link.iter().all(|config| link_control(config) == 0));
+    }
+}
@@ -0,0 +1,1021 @@
+use crate::capability::{
+    find_pci_exp_link_capabilities, find_pci_exp_link_control, find_pci_exp_link_status,
+    find_pci_l1ss, is_pcie_downstream_port,
+};
+use crate::config::{
+    read_config_u16, read_config_u32, reread_config_u16, write_config_u16, write_config_u32,
+    PciConfig,
+};
+use crate::decode::{
+    aspm_control_name, bit_difference_names, l1ss_control_name, link_capabilities_aspm_support,
+    PCI_EXP_LNKCTL_NAMES,
+};
+use crate::error::Error;
+use crate::l1ss::{calc_l12_timing, L12Timing};
+use crate::pcie::{decode_pcie_capability, PciePortType};
+use crate::regs::*;
+
+pub const PCIE_LINK_RETRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1000);
+
+/// Link Control and L1 PM Substates bits to change; each mask selects the bits taken from its flags.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AspmRequest {
+    pub mask: u16,
+    pub flags: u16,
+    pub l1ss_mask: u32,
+    pub l1ss_flags: u32,
+}
+
+/// Register values read from one function and the values `apply_aspm_link` will write.
+#[derive(Debug)]
+pub struct AspmPlan {
+    pub link_capabilities_value: u32,
+    pub link_control_offset: usize,
+    pub link_control_old_value: u16,
+    pub link_control_new_value: u16,
+    pub l1ss_control_offset: Option<usize>,
+    pub l1ss_control_old_value: u32,
+    pub l1ss_control_new_value: u32,
+    pub l1ss_control2_old_value: u32,
+    pub l1ss_control2_new_value: u32,
+    pub l12_timing: Option<L12Timing>,
+}
+
+fn link_control_bits(request: &AspmRequest, config: &PciConfig) -> (u16, u16) {
+    // Enable Clock Power Management is reserved for Downstream Ports.
+    if is_pcie_downstream_port(&config.buffer) {
+        (
+            request.mask & !PCI_EXP_LNKCTL_CLKREQ_EN,
+            request.flags & !PCI_EXP_LNKCTL_CLKREQ_EN,
+        )
+    } else {
+        (request.mask, request.flags)
+    }
+}
+
+pub fn unsupported_aspm_names(request: &AspmRequest, config: &PciConfig) -> Vec<String> {
+    let link_capabilities_value = find_pci_exp_link_capabilities(&config.buffer)
+        .map(|link_capabilities_range| {
+            read_config_u32(&config.buffer, link_capabilities_range.start)
+        })
+        .unwrap_or(0);
+
+    let l1ss_support = find_pci_l1ss(&config.buffer)
+        .ok()
+        .flatten()
+        .map(|l1ss_range| read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CAP))
+        .filter(|l1ss_capabilities_value| l1ss_capabilities_value & PCI_L1SS_CAP_L1_PM_SS != 0)
+        .map(|l1ss_capabilities_value| l1ss_capabilities_value & PCI_L1SS_CTL1_L1SS_MASK)
+        .unwrap_or(0);
+
+    let (_, link_control_flags) = link_control_bits(request, config);
+    let aspm_unsupported = link_control_flags
+        & PCI_EXP_LNKCTL_ASPMC
+        & !link_capabilities_aspm_support(link_capabilities_value);
+    let l1ss_unsupported = request.l1ss_flags & !l1ss_support;
+
+    let mut unsupported_names = Vec::new();
+
+    if aspm_unsupported != 0 {
+        unsupported_names.push(format!("aspm {}", aspm_control_name(aspm_unsupported)));
+    }
+
+    if link_control_flags & PCI_EXP_LNKCTL_CLKREQ_EN != 0
+        && link_capabilities_value & PCI_EXP_LNKCAP_CLKPM == 0
+    {
+        unsupported_names.push("clock power management".to_string());
+    }
+
+    if l1ss_unsupported != 0 {
+        unsupported_names.push(l1ss_control_name(l1ss_unsupported));
+    }
+
+    unsupported_names
+}
+
+/// An ASPM state whose exit latency on one link exceeds what an endpoint below it accepts.
+#[derive(Debug, Clone)]
+pub struct LatencyViolation {
+    pub aspm_control: u16,
+    pub link: String,
+    pub latency_ns: u32,
+    pub acceptable_latency_ns: u32,
+}
+
+impl std::fmt::Display for LatencyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "aspm {} exit latency {}ns below {} exceeds acceptable latency {}ns",
+            aspm_control_name(self.aspm_control),
+            self.latency_ns,
+            self.link,
+            self.acceptable_latency_ns
+        )
+    }
+}
+
+/// Checks the ASPM states in `aspm_control` against the acceptable latencies of `endpoint`,
+/// the way the kernel's pcie_aspm_check_latency() does, over `links` from
+/// `open_pci_path_to_root`. The states go on the endpoint's own link, the first one; links
+/// further up only count in the states they already have enabled. Components other than
+/// endpoints have nothing to check.
+pub fn aspm_latency_violations(
+    aspm_control: u16,
+    endpoint: &PciConfig,
+    links: &[(PciConfig, PciConfig)],
+) -> Result<Vec<LatencyViolation>, Error> {
+    let endpoint_capability =
+        decode_pcie_capability(&endpoint.buffer).map_err(|err| err.with_subject(&endpoint.path))?;
+
+    if !matches!(
+        endpoint_capability.port_type,
+        PciePortType::Endpoint | PciePortType::LegacyEndpoint
+    ) {
+        return Ok(Vec::new());
+    }
+
+    let device_capabilities = endpoint_capability.device_capabilities;
+    let mut violations = Vec::new();
+    let mut l1_switch_latency_ns = 0;
+
+    for (index, (upstream_config, downstream_config)) in links.iter().enumerate() {
+        let mut l0s_latency_ns = 0;
+        let mut l1_latency_ns = 0;
+        let mut any_aspm_control = 0;
+        let mut all_aspm_control = PCI_EXP_LNKCTL_ASPMC;
+
+        for config in [upstream_config, downstream_config] {
+            let capability = decode_pcie_capability(&config.buffer)
+                .map_err(|err| err.with_subject(&config.path))?;
+            let link_capabilities = capability.link_capabilities;
+
+            any_aspm_control |= capability.link_control.aspm_control;
+            all_aspm_control &= capability.link_control.aspm_control;
+
+            // The open-ended encodings count as "more than 4us" and "more than 64us".
+            l0s_latency_ns =
+                l0s_latency_ns.max(link_capabilities.l0s_exit_latency_ns.unwrap_or(5000));
+            l1_latency_ns =
+                l1_latency_ns.max(link_capabilities.l1_exit_latency_ns.unwrap_or(65000));
+        }
+
+        // L0s works per direction, while L1 needs both ends.
+        let link_aspm_control = if index == 0 {
+            aspm_control
+        } else {
+            (any_aspm_control & PCI_EXP_LNKCTL_ASPM_L0S)
+                | (all_aspm_control & PCI_EXP_LNKCTL_ASPM_L1)
+        };
+
+        // Every switch on the way to the root complex adds 1us to the L1 exit; L0s exits are per link.
+        let checks = [
+            (
+                PCI_EXP_LNKCTL_ASPM_L0S,
+                l0s_latency_ns,
+                device_capabilities.l0s_acceptable_latency_ns,
+            ),
+            (
+                PCI_EXP_LNKCTL_ASPM_L1,
+                l1_latency_ns + l1_switch_latency_ns,
+                device_capabilities.l1_acceptable_latency_ns,
+            ),
+        ];
+
+        for (state, latency_ns, acceptable_latency_ns) in checks {
+            let Some(acceptable_latency_ns) = acceptable_latency_ns else {
+                continue;
+            };
+
+            if link_aspm_control & state != 0 && latency_ns > acceptable_latency_ns {
+                violations.push(LatencyViolation {
+                    aspm_control: state,
+                    link: upstream_config.path.clone(),
+                    latency_ns,
+                    acceptable_latency_ns,
+                });
+            }
+        }
+
+        l1_switch_latency_ns += 1000;
+    }
+
+    Ok(violations)
+}
+
+pub fn plan_aspm(request: &AspmRequest, config: &PciConfig) -> Result<AspmPlan, Error> {
+    let link_control_range =
+        find_pci_exp_link_control(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+    let link_capabilities_range = find_pci_exp_link_capabilities(&config.buffer)
+        .map_err(|err| err.with_subject(&config.path))?;
+    let link_control_old_value = read_config_u16(&config.buffer, link_control_range.start);
+    let (link_control_mask, link_control_flags) = link_control_bits(request, config);
+
+    let mut plan = AspmPlan {
+        link_capabilities_value: read_config_u32(&config.buffer, link_capabilities_range.start),
+        link_control_offset: link_control_range.start,
+        link_control_old_value,
+        link_control_new_value: (link_control_old_value & !link_control_mask) | link_control_flags,
+        l1ss_control_offset: None,
+        l1ss_control_old_value: 0,
+        l1ss_control_new_value: 0,
+        l1ss_control2_old_value: 0,
+        l1ss_control2_new_value: 0,
+        l12_timing: None,
+    };
+
+    if request.l1ss_mask != 0 {
+        let Some(l1ss_range) =
+            find_pci_l1ss(&config.buffer).map_err(|err| err.with_subject(&config.path))?
+        else {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "unable to find l1 pm substates capability",
+            ));
+        };
+
+        let l1ss_control_offset = l1ss_range.start + PCI_L1SS_CTL1;
+        let l1ss_control_old_value = read_config_u32(&config.buffer, l1ss_control_offset);
+        let l1ss_control_new_value =
+            (l1ss_control_old_value & !request.l1ss_mask) | request.l1ss_flags;
+        let l1ss_control2_value = read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL2);
+
+        plan.l1ss_control_offset = Some(l1ss_control_offset);
+        plan.l1ss_control_old_value = l1ss_control_old_value;
+        plan.l1ss_control_new_value = l1ss_control_new_value;
+        plan.l1ss_control2_old_value = l1ss_control2_value;
+        plan.l1ss_control2_new_value = l1ss_control2_value;
+    }
+
+    Ok(plan)
+}
+
+/// Adds the L1.2 timing `calc_l12_timing` works out between `link[0]` and each function below
+/// it to `plans`, so `apply_aspm_link` writes it along with the L1 PM Substates enables.
+pub fn plan_l12_timing(link: &[PciConfig], plans: &mut [AspmPlan]) -> Result<(), Error> {
+    // Common_Mode_Restore_Time is only meaningful in the upstream port.
+    let upstream_timing_mask = PCI_L1SS_CTL1_CM_RESTORE_TIME
+        | PCI_L1SS_CTL1_LTR_L12_TH_VALUE
+        | PCI_L1SS_CTL1_LTR_L12_TH_SCALE;
+    let downstream_timing_mask = PCI_L1SS_CTL1_LTR_L12_TH_VALUE | PCI_L1SS_CTL1_LTR_L12_TH_SCALE;
+
+    for downstream_index in 1..link.len() {
+        let timing = calc_l12_timing(&link[0], &link[downstream_index])?;
+        let ends = [
+            (0, timing.upstream_l1ss, upstream_timing_mask),
+            (
+                downstream_index,
+                timing.downstream_l1ss,
+                downstream_timing_mask,
+            ),
+        ];
+
+        for (index, l1ss, timing_mask) in ends {
+            let (config, plan) = (&link[index], &mut plans[index]);
+
+            if plan.l1ss_control_offset.is_none() {
+                let l1ss_control_value = read_config_u32(&config.buffer, l1ss + PCI_L1SS_CTL1);
+                let l1ss_control2_value = read_config_u32(&config.buffer, l1ss + PCI_L1SS_CTL2);
+
+                plan.l1ss_control_offset = Some(l1ss + PCI_L1SS_CTL1);
+                plan.l1ss_control_old_value = l1ss_control_value;
+                plan.l1ss_control_new_value = l1ss_control_value;
+                plan.l1ss_control2_old_value = l1ss_control2_value;
+            }
+
+            plan.l1ss_control_new_value = (plan.l1ss_control_new_value & !timing_mask)
+                | (timing.l1ss_control1_timing_value & timing_mask);
+            plan.l1ss_control2_new_value = timing.l1ss_control2_value;
+            plan.l12_timing = Some(timing);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn apply_aspm_link(link: &mut [PciConfig], plans: &[AspmPlan]) -> Result<(), Error> {
+    let l1ss_changing = plans.iter().any(|plan| {
+        plan.l1ss_control_old_value != plan.l1ss_control_new_value
+            || plan.l1ss_control2_old_value != plan.l1ss_control2_new_value
+    });
+
+    // L1.2 must be disabled on both ends while its timing parameters change (PCIe r5.0, sec 5.5.4).
+    let l12_timing_changing = plans.iter().any(|plan| {
+        (plan.l1ss_control_old_value ^ plan.l1ss_control_new_value) & !PCI_L1SS_CTL1_L1SS_MASK != 0
+            || plan.l1ss_control2_old_value != plan.l1ss_control2_new_value
+    });
+    let l1ss_kept_enables = if l12_timing_changing {
+        PCI_L1SS_CTL1_L1SS_MASK & !PCI_L1SS_CTL1_L1_2_MASK
+    } else {
+        PCI_L1SS_CTL1_L1SS_MASK
+    };
+
+    // L1 PM Substates must not be reconfigured while ASPM L1 is enabled.
+    if l1ss_changing {
+        for (config, plan) in link.iter_mut().zip(plans).rev() {
+            let link_control_value = read_config_u16(&config.buffer, plan.link_control_offset);
+
+            if link_control_value & PCI_EXP_LNKCTL_ASPM_L1 != 0 {
+                write_config_u16(
+                    config,
+                    plan.link_control_offset,
+                    link_control_value & !PCI_EXP_LNKCTL_ASPM_L1,
+                )?;
+            }
+        }
+    }
+
+    // Disable on the downstream component first, then enable on the upstream component first.
+    for (config, plan) in link.iter_mut().zip(plans).rev() {
+        if let Some(l1ss_control_offset) = plan.l1ss_control_offset {
+            let l1ss_control_value = read_config_u32(&config.buffer, l1ss_control_offset);
+            let l1ss_enables = l1ss_control_value & plan.l1ss_control_new_value & l1ss_kept_enables;
+            let l1ss_disabled_value =
+                (l1ss_control_value & !PCI_L1SS_CTL1_L1SS_MASK) | l1ss_enables;
+
+            if l1ss_disabled_value != l1ss_control_value {
+                write_config_u32(config, l1ss_control_offset, l1ss_disabled_value)?;
+            }
+        }
+    }
+
+    for (config, plan) in link.iter_mut().zip(plans) {
+        if let Some(l1ss_control_offset) = plan.l1ss_control_offset {
+            let l1ss_control2_offset = l1ss_control_offset - PCI_L1SS_CTL1 + PCI_L1SS_CTL2;
+
+            if read_config_u32(&config.buffer, l1ss_control2_offset) != plan.l1ss_control2_new_value
+            {
+                write_config_u32(config, l1ss_control2_offset, plan.l1ss_control2_new_value)?;
+            }
+        }
+    }
+
+    // The timing fields of Control 1 go in before the enables that depend on them.
+    for (config, plan) in link.iter_mut().zip(plans) {
+        if let Some(l1ss_control_offset) = plan.l1ss_control_offset {
+            let l1ss_control_value = read_config_u32(&config.buffer, l1ss_control_offset);
+            let l1ss_timing_value = (plan.l1ss_control_new_value & !PCI_L1SS_CTL1_L1SS_MASK)
+                | (l1ss_control_value & PCI_L1SS_CTL1_L1SS_MASK);
+
+            if l1ss_timing_value != l1ss_control_value {
+                write_config_u32(config, l1ss_control_offset, l1ss_timing_value)?;
+            }
+        }
+    }
+
+    for (config, plan) in link.iter_mut().zip(plans) {
+        if let Some(l1ss_control_offset) = plan.l1ss_control_offset {
+            if read_config_u32(&config.buffer, l1ss_control_offset) != plan.l1ss_control_new_value {
+                write_config_u32(config, l1ss_control_offset, plan.l1ss_control_new_value)?;
+            }
+        }
+    }
+
+    for (config, plan) in link.iter_mut().zip(plans).rev() {
+        let link_control_value = read_config_u16(&config.buffer, plan.link_control_offset);
+
+        if link_control_value & plan.link_control_new_value != link_control_value {
+            write_config_u16(
+                config,
+                plan.link_control_offset,
+                link_control_value & plan.link_control_new_value,
+            )?;
+        }
+    }
+
+    for (config, plan) in link.iter_mut().zip(plans) {
+        if read_config_u16(&config.buffer, plan.link_control_offset) != plan.link_control_new_value
+        {
+            write_config_u16(
+                config,
+                plan.link_control_offset,
+                plan.link_control_new_value,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A function whose Link Control register reads back different from what was planned.
+#[derive(Debug, Clone)]
+pub struct LinkControlMismatch {
+    pub path: String,
+    pub expected_value: u16,
+    pub actual_value: u16,
+}
+
+impl std::fmt::Display for LinkControlMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: link control reads 0x{:04x} instead of 0x{:04x} ({})",
+            self.path,
+            self.actual_value,
+            self.expected_value,
+            bit_difference_names(
+                self.expected_value as u32,
+                self.actual_value as u32,
+                &PCI_EXP_LNKCTL_NAMES
+            )
+        )
+    }
+}
+
+/// Re-reads Link Control from every function of `link`, since devices may hardwire
+/// ASPM control bits or ignore the write altogether.
+pub fn verify_link_control(
+    link: &mut [PciConfig],
+    plans: &[AspmPlan],
+) -> Result<Vec<LinkControlMismatch>, Error> {
+    let mut mismatches = Vec::new();
+
+    for (config, plan) in link.iter_mut().zip(plans) {
+        let link_control_value = reread_config_u16(config, plan.link_control_offset)?;
+
+        if link_control_value != plan.link_control_new_value {
+            mismatches.push(LinkControlMismatch {
+                path: config.path.clone(),
+                expected_value: plan.link_control_new_value,
+                actual_value: link_control_value,
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+pub fn has_common_clock(link: &[PciConfig]) -> Result<bool, Error> {
+    for config in link {
+        let link_status_range = find_pci_exp_link_status(&config.buffer)
+            .map_err(|err| err.with_subject(&config.path))?;
+
+        if read_config_u16(&config.buffer, link_status_range.start) & PCI_EXP_LNKSTA_SLC == 0 {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn wait_for_link_training(config: &mut PciConfig, link_status_offset: usize) -> Result<(), Error> {
+    let start = std::time::Instant::now();
+
+    while reread_config_u16(config, link_status_offset)? & PCI_EXP_LNKSTA_LT != 0 {
+        if start.elapsed() > PCIE_LINK_RETRAIN_TIMEOUT {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "timed out waiting for link training",
+            ));
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    Ok(())
+}
+
+pub fn retrain_link(config: &mut PciConfig) -> Result<(), Error> {
+    let link_control_range =
+        find_pci_exp_link_control(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+    let link_status_range =
+        find_pci_exp_link_status(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+
+    // Training that started before the parameters changed would not pick them up.
+    wait_for_link_training(config, link_status_range.start)?;
+
+    let link_control_value = read_config_u16(&config.buffer, link_control_range.start);
+
+    write_config_u16(
+        config,
+        link_control_range.start,
+        link_control_value | PCI_EXP_LNKCTL_RL,
+    )?;
+
+    // Retrain Link always reads as zero, so refresh the cached value.
+    reread_config_u16(config, link_control_range.start)?;
+
+    wait_for_link_training(config, link_status_range.start)
+}
+
+pub fn configure_common_clock(link: &mut [PciConfig], plans: &[AspmPlan]) -> Result<(), Error> {
+    let changing = link.iter().zip(plans).any(|(config, plan)| {
+        read_config_u16(&config.buffer, plan.link_control_offset) & PCI_EXP_LNKCTL_CCC
+            != plan.link_control_new_value & PCI_EXP_LNKCTL_CCC
+    });
+
+    if !changing {
+        return Ok(());
+    }
+
+    // Configure the downstream component before the upstream port, then retrain from the upstream port.
+    for (config, plan) in link.iter_mut().zip(plans).rev() {
+        let link_control_value = read_config_u16(&config.buffer, plan.link_control_offset);
+
+        write_config_u16(
+            config,
+            plan.link_control_offset,
+            (link_control_value & !PCI_EXP_LNKCTL_CCC)
+                | (plan.link_control_new_value & PCI_EXP_LNKCTL_CCC),
+        )?;
+    }
+
+    retrain_link(&mut link[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::field_prep;
+    use crate::config::testing::*;
+
+    const LINK_CAPABILITIES_L1: u32 = 0x0000_0800;
+    const LINK_CAPABILITIES_L0S_L1: u32 = 0x0000_0c00;
+    const L1SS_CAPABILITIES: u32 = PCI_L1SS_CAP_L1_PM_SS | PCI_L1SS_CTL1_L1SS_MASK;
+    const L1SS_TIMING: u32 = 0x4001_2800;
+    const LINK_CONTROL_L1: u16 = PCI_EXP_LNKCTL_ASPM_L1;
+
+    fn enable_request(flags: u16) -> AspmRequest {
+        AspmRequest {
+            mask: flags,
+            flags,
+            ..AspmRequest::default()
+        }
+    }
+
+    /// A root port and an endpoint below it, both with L1 and every L1 PM Substate enabled.
+    fn l1ss_link(log: &WriteLog, control1_value: u32, control2_value: u32) -> Vec<PciConfig> {
+        [
+            ("rp", PCI_EXP_TYPE_ROOT_PORT),
+            ("ep", PCI_EXP_TYPE_ENDPOINT),
+        ]
+        .into_iter()
+        .map(|(path, port_type)| {
+            TestFunction::new(port_type)
+                .link_capabilities(LINK_CAPABILITIES_L1)
+                .link_control(LINK_CONTROL_L1)
+                .l1ss(L1SS_CAPABILITIES, control1_value, control2_value)
+                .open(path, log)
+        })
+        .collect()
+    }
+
+    fn plan_link(request: &AspmRequest, link: &[PciConfig]) -> Vec<AspmPlan> {
+        link.iter()
+            .map(|config| plan_aspm(request, config).unwrap())
+            .collect()
+    }
+
+    fn write(path: &str, offset: usize, value: u32) -> (String, usize, u32) {
+        (path.to_string(), offset, value)
+    }
+
+    #[test]
+    fn apply_aspm_link_enables_upstream_first_and_disables_downstream_first() {
+        let log = WriteLog::default();
+        let mut link = l1ss_link(&log, 0, 0);
+        let request = AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPMC,
+            flags: PCI_EXP_LNKCTL_ASPM_L0S,
+            ..AspmRequest::default()
+        };
+        let plans = plan_link(&request, &link);
+
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        let l0s = PCI_EXP_LNKCTL_ASPM_L0S as u32;
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                write("ep", TEST_LINK_CONTROL, 0),
+                write("rp", TEST_LINK_CONTROL, 0),
+                write("rp", TEST_LINK_CONTROL, l0s),
+                write("ep", TEST_LINK_CONTROL, l0s),
+            ]
+        );
+
+        log.borrow_mut().clear();
+
+        let plans = plan_link(&enable_request(PCI_EXP_LNKCTL_ASPMC), &link);
+
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                write("rp", TEST_LINK_CONTROL, PCI_EXP_LNKCTL_ASPMC as u32),
+                write("ep", TEST_LINK_CONTROL, PCI_EXP_LNKCTL_ASPMC as u32),
+            ]
+        );
+
+        // Nothing to write once the link is where the plans want it.
+        log.borrow_mut().clear();
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn apply_aspm_link_keeps_l1ss_timing_when_disabling() {
+        let log = WriteLog::default();
+        let mut link = l1ss_link(&log, L1SS_TIMING | PCI_L1SS_CTL1_L1SS_MASK, 0x28);
+        let request = AspmRequest {
+            l1ss_mask: PCI_L1SS_CTL1_L1SS_MASK,
+            l1ss_flags: PCI_L1SS_CTL1_ASPM_L1_1,
+            ..AspmRequest::default()
+        };
+        let plans = plan_link(&request, &link);
+
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        let l1ss_value = L1SS_TIMING | PCI_L1SS_CTL1_ASPM_L1_1;
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                write("ep", TEST_LINK_CONTROL, 0),
+                write("rp", TEST_LINK_CONTROL, 0),
+                write("ep", TEST_L1SS_CONTROL1, l1ss_value),
+                write("rp", TEST_L1SS_CONTROL1, l1ss_value),
+                write("rp", TEST_LINK_CONTROL, LINK_CONTROL_L1 as u32),
+                write("ep", TEST_LINK_CONTROL, LINK_CONTROL_L1 as u32),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_aspm_link_disables_l12_while_changing_timing() {
+        let log = WriteLog::default();
+        let mut link = l1ss_link(&log, L1SS_TIMING | PCI_L1SS_CTL1_L1SS_MASK, 0x28);
+        let request = AspmRequest {
+            l1ss_mask: PCI_L1SS_CTL1_L1SS_MASK,
+            l1ss_flags: PCI_L1SS_CTL1_L1SS_MASK,
+            ..AspmRequest::default()
+        };
+        let mut plans = plan_link(&request, &link);
+        let new_timing = 0x6002_3c00;
+
+        for plan in &mut plans {
+            plan.l1ss_control_new_value = new_timing | PCI_L1SS_CTL1_L1SS_MASK;
+            plan.l1ss_control2_new_value = 0x50;
+        }
+
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        let l11_only = PCI_L1SS_CTL1_L1SS_MASK & !PCI_L1SS_CTL1_L1_2_MASK;
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                write("ep", TEST_LINK_CONTROL, 0),
+                write("rp", TEST_LINK_CONTROL, 0),
+                write("ep", TEST_L1SS_CONTROL1, L1SS_TIMING | l11_only),
+                write("rp", TEST_L1SS_CONTROL1, L1SS_TIMING | l11_only),
+                write("rp", TEST_L1SS_CONTROL2, 0x50),
+                write("ep", TEST_L1SS_CONTROL2, 0x50),
+                write("rp", TEST_L1SS_CONTROL1, new_timing | l11_only),
+                write("ep", TEST_L1SS_CONTROL1, new_timing | l11_only),
+                write(
+                    "rp",
+                    TEST_L1SS_CONTROL1,
+                    new_timing | PCI_L1SS_CTL1_L1SS_MASK
+                ),
+                write(
+                    "ep",
+                    TEST_L1SS_CONTROL1,
+                    new_timing | PCI_L1SS_CTL1_L1SS_MASK
+                ),
+                write("rp", TEST_LINK_CONTROL, LINK_CONTROL_L1 as u32),
+                write("ep", TEST_LINK_CONTROL, LINK_CONTROL_L1 as u32),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_l12_timing_covers_both_ends() {
+        let log = WriteLog::default();
+        let l1ss_capabilities_value = L1SS_CAPABILITIES
+            | field_prep(PCI_L1SS_CAP_CM_RESTORE_TIME, 20)
+            | field_prep(PCI_L1SS_CAP_P_PWR_ON_VALUE, 5);
+        let link = [
+            ("rp", PCI_EXP_TYPE_ROOT_PORT),
+            ("ep", PCI_EXP_TYPE_ENDPOINT),
+        ]
+        .into_iter()
+        .map(|(path, port_type)| {
+            TestFunction::new(port_type)
+                .l1ss(
+                    l1ss_capabilities_value,
+                    L1SS_TIMING | PCI_L1SS_CTL1_L1SS_MASK,
+                    0,
+                )
+                .open(path, &log)
+        })
+        .collect::<Vec<_>>();
+        let mut plans = plan_link(&AspmRequest::default(), &link);
+
+        plan_l12_timing(&link, &mut plans).unwrap();
+
+        // 2us + 4us + 20us common mode restore + 10us power on = 36us.
+        let threshold = field_prep(PCI_L1SS_CTL1_LTR_L12_TH_VALUE, 36)
+            | field_prep(PCI_L1SS_CTL1_LTR_L12_TH_SCALE, 2);
+        let downstream_cm_restore_time = L1SS_TIMING & PCI_L1SS_CTL1_CM_RESTORE_TIME;
+
+        assert_eq!(
+            plans[0].l1ss_control_new_value,
+            field_prep(PCI_L1SS_CTL1_CM_RESTORE_TIME, 20) | threshold | PCI_L1SS_CTL1_L1SS_MASK
+        );
+        assert_eq!(
+            plans[1].l1ss_control_new_value,
+            downstream_cm_restore_time | threshold | PCI_L1SS_CTL1_L1SS_MASK
+        );
+
+        for plan in &plans {
+            assert_eq!(plan.l1ss_control_offset, Some(TEST_L1SS_CONTROL1));
+            assert_eq!(
+                plan.l1ss_control_old_value,
+                L1SS_TIMING | PCI_L1SS_CTL1_L1SS_MASK
+            );
+            assert_eq!(plan.l1ss_control2_old_value, 0);
+            assert_eq!(plan.l1ss_control2_new_value, 0x28);
+            assert!(plan.l12_timing.is_some());
+        }
+
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn verify_link_control_reports_hardwired_bits() {
+        let log = WriteLog::default();
+        let mut link = [
+            TestFunction::new(PCI_EXP_TYPE_ROOT_PORT).open("rp", &log),
+            TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+                .hardwire_u16(TEST_LINK_CONTROL, PCI_EXP_LNKCTL_ASPM_L1)
+                .open("ep", &log),
+        ];
+        let plans = plan_link(&enable_request(PCI_EXP_LNKCTL_ASPM_L1), &link);
+
+        apply_aspm_link(&mut link, &plans).unwrap();
+
+        let mismatches = verify_link_control(&mut link, &plans).unwrap();
+
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "ep: link control reads 0x0000 instead of 0x0002 (-ASPM_L1)"
+        );
+
+        // The re-read value is what later plans start from.
+        assert_eq!(read_config_u16(&link[1].buffer, TEST_LINK_CONTROL), 0);
+        assert!(verify_link_control(&mut link[..1], &plans[..1])
+            .unwrap()
+            .is_empty());
+    }
+
+    /// A root port whose Retrain Link bit always reads as zero, and an endpoint below it.
+    fn common_clock_link(log: &WriteLog, link_status_value: u16) -> Vec<PciConfig> {
+        vec![
+            TestFunction::new(PCI_EXP_TYPE_ROOT_PORT)
+                .link_status(link_status_value)
+                .hardwire_u16(TEST_LINK_CONTROL, PCI_EXP_LNKCTL_RL)
+                .open("rp", log),
+            TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+                .link_status(link_status_value)
+                .open("ep", log),
+        ]
+    }
+
+    #[test]
+    fn has_common_clock_needs_slot_clock_on_both_ends() {
+        let log = WriteLog::default();
+
+        assert!(has_common_clock(&common_clock_link(&log, PCI_EXP_LNKSTA_SLC)).unwrap());
+
+        let mut link = common_clock_link(&log, PCI_EXP_LNKSTA_SLC);
+
+        link[1] = TestFunction::new(PCI_EXP_TYPE_ENDPOINT).open("ep", &log);
+
+        assert!(!has_common_clock(&link).unwrap());
+    }
+
+    #[test]
+    fn configure_common_clock_sets_downstream_first_then_retrains() {
+        let log = WriteLog::default();
+        let mut link = common_clock_link(&log, PCI_EXP_LNKSTA_SLC);
+        let mut plans = plan_link(&AspmRequest::default(), &link);
+
+        for plan in &mut plans {
+            plan.link_control_new_value |= PCI_EXP_LNKCTL_CCC;
+        }
+
+        configure_common_clock(&mut link, &plans).unwrap();
+
+        let ccc = PCI_EXP_LNKCTL_CCC as u32;
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                write("ep", TEST_LINK_CONTROL, ccc),
+                write("rp", TEST_LINK_CONTROL, ccc),
+                write("rp", TEST_LINK_CONTROL, ccc | PCI_EXP_LNKCTL_RL as u32),
+            ]
+        );
+        assert_eq!(
+            read_config_u16(&link[0].buffer, TEST_LINK_CONTROL),
+            PCI_EXP_LNKCTL_CCC
+        );
+
+        // Already configured, so no retrain either.
+        log.borrow_mut().clear();
+        configure_common_clock(&mut link, &plans).unwrap();
+
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn retrain_link_times_out_while_training() {
+        let log = WriteLog::default();
+        let mut link = common_clock_link(&log, PCI_EXP_LNKSTA_LT);
+
+        assert!(retrain_link(&mut link[0]).is_err());
+        assert!(log.borrow().is_empty());
+    }
+
+    /// The endpoint's link below a switch, then the link from the root port to the switch,
+    /// each end with L1 exit latency `l1_exit_encodings` and Link Control `link_control_values`.
+    fn path_to_root(
+        log: &WriteLog,
+        l1_exit_encodings: [u32; 2],
+        link_control_values: [[u16; 2]; 2],
+    ) -> Vec<(PciConfig, PciConfig)> {
+        let ports = [
+            [
+                ("dsp", PCI_EXP_TYPE_DOWNSTREAM),
+                ("ep", PCI_EXP_TYPE_ENDPOINT),
+            ],
+            [
+                ("rp", PCI_EXP_TYPE_ROOT_PORT),
+                ("usp", PCI_EXP_TYPE_UPSTREAM),
+            ],
+        ];
+
+        ports
+            .into_iter()
+            .zip(l1_exit_encodings.into_iter().zip(link_control_values))
+            .map(|(ends, (l1_exit_encoding, link_control_values))| {
+                let [upstream, downstream] = [0, 1].map(|end| {
+                    let (path, port_type) = ends[end];
+
+                    TestFunction::new(port_type)
+                        .link_capabilities(
+                            LINK_CAPABILITIES_L0S_L1
+                                | field_prep(PCI_EXP_LNKCAP_L1EL, l1_exit_encoding),
+                        )
+                        .link_control(link_control_values[end])
+                        // 4us acceptable L1 exit latency, checked only for the endpoint.
+                        .device_capabilities(field_prep(PCI_EXP_DEVCAP_L1, 2))
+                        .open(path, log)
+                });
+
+                (upstream, downstream)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aspm_latency_violations_counts_own_link_and_enabled_links_above() {
+        let log = WriteLog::default();
+        let l1 = PCI_EXP_LNKCTL_ASPM_L1;
+
+        // 1us on the endpoint's link, then 4us plus 1us for the switch above it.
+        let links = path_to_root(&log, [0, 2], [[0, 0], [0, 0]]);
+
+        assert!(aspm_latency_violations(l1, &links[0].1, &links)
+            .unwrap()
+            .is_empty());
+
+        // L1 on one end of a link is no L1 at all.
+        let links = path_to_root(&log, [0, 2], [[0, 0], [l1, 0]]);
+
+        assert!(aspm_latency_violations(l1, &links[0].1, &links)
+            .unwrap()
+            .is_empty());
+
+        let links = path_to_root(&log, [0, 2], [[0, 0], [l1, l1]]);
+        let violations = aspm_latency_violations(l1, &links[0].1, &links).unwrap();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].link, "rp");
+        assert_eq!(violations[0].latency_ns, 5000);
+        assert_eq!(violations[0].acceptable_latency_ns, 4000);
+
+        // The requested states go on the endpoint's link whatever it has enabled now.
+        let links = path_to_root(&log, [3, 0], [[0, 0], [0, 0]]);
+        let violations = aspm_latency_violations(l1, &links[0].1, &links).unwrap();
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].link, "dsp");
+        assert_eq!(violations[0].latency_ns, 8000);
+
+        // Only endpoints state acceptable latencies.
+        assert!(aspm_latency_violations(l1, &links[1].1, &links[1..])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn unsupported_aspm_names_checks_link_capabilities() {
+        let log = WriteLog::default();
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_capabilities(LINK_CAPABILITIES_L1)
+            .open("ep", &log);
+
+        assert!(
+            unsupported_aspm_names(&enable_request(PCI_EXP_LNKCTL_ASPM_L1), &config).is_empty()
+        );
+        assert_eq!(
+            unsupported_aspm_names(&enable_request(PCI_EXP_LNKCTL_ASPMC), &config),
+            ["aspm L0s"]
+        );
+
+        // Disabling a state the link does not support is always fine.
+        let request = AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPMC,
+            flags: 0,
+            ..AspmRequest::default()
+        };
+
+        assert!(unsupported_aspm_names(&request, &config).is_empty());
+
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_capabilities(LINK_CAPABILITIES_L0S_L1)
+            .open("ep", &log);
+
+        assert!(unsupported_aspm_names(&enable_request(PCI_EXP_LNKCTL_ASPMC), &config).is_empty());
+    }
+
+    #[test]
+    fn unsupported_aspm_names_checks_l1ss_capabilities() {
+        let log = WriteLog::default();
+        let request = AspmRequest {
+            l1ss_mask: PCI_L1SS_CTL1_L1SS_MASK,
+            l1ss_flags: PCI_L1SS_CTL1_ASPM_L1_1 | PCI_L1SS_CTL1_ASPM_L1_2,
+            ..AspmRequest::default()
+        };
+
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            // Support bits sit where the enable bits sit in Control 1.
+            .l1ss(PCI_L1SS_CAP_L1_PM_SS | PCI_L1SS_CTL1_ASPM_L1_1, 0, 0)
+            .open("ep", &log);
+
+        assert_eq!(unsupported_aspm_names(&request, &config), ["ASPM_L1.2"]);
+
+        // Without the L1 PM Substates Supported bit the other bits mean nothing.
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .l1ss(PCI_L1SS_CTL1_L1SS_MASK, 0, 0)
+            .open("ep", &log);
+
+        assert_eq!(
+            unsupported_aspm_names(&request, &config),
+            ["ASPM_L1.1 ASPM_L1.2"]
+        );
+
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT).open("ep", &log);
+
+        assert_eq!(
+            unsupported_aspm_names(&request, &config),
+            ["ASPM_L1.1 ASPM_L1.2"]
+        );
+    }
+
+    #[test]
+    fn unsupported_aspm_names_checks_clock_pm() {
+        let log = WriteLog::default();
+        let request = enable_request(PCI_EXP_LNKCTL_CLKREQ_EN);
+
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT).open("ep", &log);
+
+        assert_eq!(
+            unsupported_aspm_names(&request, &config),
+            ["clock power management"]
+        );
+
+        let config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_capabilities(PCI_EXP_LNKCAP_CLKPM)
+            .open("ep", &log);
+
+        assert!(unsupported_aspm_names(&request, &config).is_empty());
+
+        // The bit is reserved in downstream ports, which never get it.
+        let config = TestFunction::new(PCI_EXP_TYPE_ROOT_PORT).open("rp", &log);
+
+        assert!(unsupported_aspm_names(&request, &config).is_empty());
+        assert_eq!(
+            plan_aspm(&request, &config).unwrap().link_control_new_value,
+            0
+        );
+    }
+}
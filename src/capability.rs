@@ -0,0 +1,258 @@
+use crate::config::{read_config_u16, read_config_u32};
+use crate::error::Error;
+use crate::regs::*;
+
+pub fn find_pci_capability(
+    config_buffer: &[u8],
+    target_capability_id: u8,
+    target_capability_length: usize,
+) -> Result<Option<std::ops::Range<usize>>, Error> {
+    let Some(capability_pointer) = config_buffer.get(PCI_CAPABILITY_LIST) else {
+        return Ok(None);
+    };
+
+    let mut capability_pointer = *capability_pointer as usize;
+
+    loop {
+        let (Some(&capability_id), Some(&next_capability_pointer)) = (
+            config_buffer.get(capability_pointer),
+            config_buffer.get(capability_pointer + 1),
+        ) else {
+            return Ok(None);
+        };
+
+        let next_capability_pointer = next_capability_pointer as usize;
+
+        if next_capability_pointer != 0 && next_capability_pointer < capability_pointer + 2 {
+            return Err(Error::without_subject(
+                "error",
+                "next capability pointer invalid",
+            ));
+        }
+
+        if capability_id == target_capability_id {
+            if (next_capability_pointer >= capability_pointer
+                && target_capability_length > next_capability_pointer - capability_pointer)
+                || (target_capability_length > config_buffer.len() - capability_pointer)
+            {
+                return Err(Error::without_subject(
+                    "error",
+                    "capability length overflow",
+                ));
+            }
+
+            return Ok(Some(
+                (capability_pointer)..(capability_pointer + target_capability_length),
+            ));
+        }
+
+        if next_capability_pointer > capability_pointer {
+            capability_pointer = next_capability_pointer;
+        } else {
+            return Ok(None);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PciExtCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: usize,
+    pub next: usize,
+}
+
+pub fn pci_ext_capabilities(config_buffer: &[u8]) -> Result<Vec<PciExtCapability>, Error> {
+    let mut capabilities = Vec::new();
+
+    if config_buffer.len() < PCI_CFG_SPACE_SIZE + PCI_EXT_CAP_HEADER_LEN {
+        return Ok(capabilities);
+    }
+
+    let mut capability_pointer = PCI_CFG_SPACE_SIZE;
+
+    loop {
+        let capability_header = read_config_u32(config_buffer, capability_pointer);
+
+        if capability_header == 0 || capability_header == 0xffffffff {
+            return Ok(capabilities);
+        }
+
+        let capability_id = capability_header as u16;
+        let capability_version = ((capability_header >> 16) & 0xf) as u8;
+        let next_capability_pointer = ((capability_header >> 20) & 0xffc) as usize;
+
+        if next_capability_pointer != 0
+            && (next_capability_pointer < capability_pointer + PCI_EXT_CAP_HEADER_LEN
+                || next_capability_pointer + PCI_EXT_CAP_HEADER_LEN > config_buffer.len())
+        {
+            return Err(Error::without_subject(
+                "error",
+                "next extended capability pointer invalid",
+            ));
+        }
+
+        capabilities.push(PciExtCapability {
+            id: capability_id,
+            version: capability_version,
+            offset: capability_pointer,
+            next: next_capability_pointer,
+        });
+
+        if next_capability_pointer == 0 {
+            return Ok(capabilities);
+        }
+
+        capability_pointer = next_capability_pointer;
+    }
+}
+
+pub fn find_pci_ext_capability(
+    config_buffer: &[u8],
+    target_capability_id: u16,
+    target_capability_length: usize,
+) -> Result<Option<std::ops::Range<usize>>, Error> {
+    let Some(capability) = pci_ext_capabilities(config_buffer)?
+        .into_iter()
+        .find(|capability| capability.id == target_capability_id)
+    else {
+        return Ok(None);
+    };
+
+    if (capability.next != 0 && target_capability_length > capability.next - capability.offset)
+        || (target_capability_length > config_buffer.len() - capability.offset)
+    {
+        return Err(Error::without_subject(
+            "error",
+            "extended capability length overflow",
+        ));
+    }
+
+    Ok(Some(
+        capability.offset..(capability.offset + target_capability_length),
+    ))
+}
+
+pub fn find_pci_exp_capability(config_buffer: &[u8]) -> Result<std::ops::Range<usize>, Error> {
+    find_pci_capability(config_buffer, PCI_CAP_ID_EXP, PCI_CAP_ID_EXP_LEN)?.ok_or_else(|| {
+        Error::without_subject("error", "unable to find pci express capability structure")
+    })
+}
+
+pub fn find_pci_exp_link_control(config_buffer: &[u8]) -> Result<std::ops::Range<usize>, Error> {
+    let capability_range = find_pci_exp_capability(config_buffer)?;
+
+    Ok((capability_range.start + PCI_EXP_LNKCTL)..(capability_range.start + PCI_EXP_LNKCTL + 2))
+}
+
+pub fn find_pci_exp_link_capabilities(
+    config_buffer: &[u8],
+) -> Result<std::ops::Range<usize>, Error> {
+    let capability_range = find_pci_exp_capability(config_buffer)?;
+
+    Ok((capability_range.start + PCI_EXP_LNKCAP)..(capability_range.start + PCI_EXP_LNKCAP + 4))
+}
+
+pub fn find_pci_exp_link_status(config_buffer: &[u8]) -> Result<std::ops::Range<usize>, Error> {
+    let capability_range = find_pci_exp_capability(config_buffer)?;
+
+    Ok((capability_range.start + PCI_EXP_LNKSTA)..(capability_range.start + PCI_EXP_LNKSTA + 2))
+}
+
+pub fn find_pci_l1ss(config_buffer: &[u8]) -> Result<Option<std::ops::Range<usize>>, Error> {
+    find_pci_ext_capability(config_buffer, PCI_EXT_CAP_ID_L1SS, PCI_EXT_CAP_L1SS_LEN)
+}
+
+pub fn find_pci_ltr(config_buffer: &[u8]) -> Result<Option<std::ops::Range<usize>>, Error> {
+    find_pci_ext_capability(config_buffer, PCI_EXT_CAP_ID_LTR, PCI_EXT_CAP_LTR_LEN)
+}
+
+pub fn is_pcie_downstream_port(config_buffer: &[u8]) -> bool {
+    let Ok(capability_range) = find_pci_exp_capability(config_buffer) else {
+        return false;
+    };
+
+    let port_type = (read_config_u16(config_buffer, capability_range.start + PCI_EXP_FLAGS)
+        & PCI_EXP_FLAGS_TYPE)
+        >> 4;
+
+    matches!(
+        port_type,
+        PCI_EXP_TYPE_ROOT_PORT | PCI_EXP_TYPE_DOWNSTREAM | PCI_EXP_TYPE_PCIE_BRIDGE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_ext_capability_header(buffer: &mut [u8], offset: usize, id: u16, next: usize) {
+        let header = id as u32 | (1 << 16) | ((next as u32) << 20);
+
+        buffer[offset..(offset + 4)].copy_from_slice(&header.to_le_bytes());
+    }
+
+    #[test]
+    fn pci_ext_capabilities_follows_chain() {
+        let mut buffer = vec![0u8; PCI_CFG_SPACE_EXP_SIZE];
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_LTR, 0x140);
+        write_ext_capability_header(&mut buffer, 0x140, PCI_EXT_CAP_ID_L1SS, 0);
+
+        let capabilities = pci_ext_capabilities(&buffer).unwrap();
+
+        assert_eq!(capabilities.len(), 2);
+        assert_eq!(capabilities[0].id, PCI_EXT_CAP_ID_LTR);
+        assert_eq!(capabilities[0].offset, 0x100);
+        assert_eq!(capabilities[1].id, PCI_EXT_CAP_ID_L1SS);
+        assert_eq!(capabilities[1].offset, 0x140);
+        assert_eq!(
+            find_pci_l1ss(&buffer).unwrap(),
+            Some(0x140..(0x140 + PCI_EXT_CAP_L1SS_LEN))
+        );
+    }
+
+    #[test]
+    fn pci_ext_capabilities_without_extended_space() {
+        assert!(pci_ext_capabilities(&[0u8; PCI_CFG_SPACE_SIZE])
+            .unwrap()
+            .is_empty());
+        assert!(pci_ext_capabilities(&vec![0xffu8; PCI_CFG_SPACE_EXP_SIZE])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn pci_ext_capabilities_rejects_loops() {
+        let mut buffer = vec![0u8; PCI_CFG_SPACE_EXP_SIZE];
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_LTR, 0x100);
+
+        assert!(pci_ext_capabilities(&buffer).is_err());
+
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_LTR, 0x140);
+        write_ext_capability_header(&mut buffer, 0x140, PCI_EXT_CAP_ID_L1SS, 0x100);
+
+        assert!(pci_ext_capabilities(&buffer).is_err());
+    }
+
+    #[test]
+    fn pci_ext_capabilities_rejects_pointer_past_buffer() {
+        let mut buffer = vec![0u8; 0x180];
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_LTR, 0x180);
+
+        assert!(pci_ext_capabilities(&buffer).is_err());
+    }
+
+    #[test]
+    fn find_pci_ext_capability_rejects_length_overflow() {
+        let mut buffer = vec![0u8; PCI_CFG_SPACE_EXP_SIZE];
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_L1SS, 0x108);
+        write_ext_capability_header(&mut buffer, 0x108, PCI_EXT_CAP_ID_LTR, 0);
+
+        assert!(find_pci_l1ss(&buffer).is_err());
+
+        let mut buffer = vec![0u8; 0x108];
+        write_ext_capability_header(&mut buffer, 0x100, PCI_EXT_CAP_ID_L1SS, 0);
+
+        assert!(find_pci_l1ss(&buffer).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+use std::os::unix::fs::FileExt;
+
+use crate::error::Error;
+use crate::regs::*;
+
+/// Byte-addressed access to the configuration space of one PCI function.
+pub trait ConfigSpace: std::fmt::Debug {
+    /// Size of the accessible configuration space in bytes.
+    fn size(&self) -> usize;
+
+    /// Fills `buffer` with the bytes starting at `offset`.
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes `data` starting at `offset`.
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+}
+
+/// Configuration space backed by a sysfs `config` file.
+#[derive(Debug)]
+pub struct SysfsConfigSpace {
+    path: String,
+    file: std::fs::File,
+    size: usize,
+}
+
+impl SysfsConfigSpace {
+    pub fn open(path: &str, writable: bool) -> Result<SysfsConfigSpace, Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(|err| Error::new("open", path, err))?;
+
+        let size = file
+            .metadata()
+            .map_err(|err| Error::new("stat", path, err))?
+            .len() as usize;
+
+        Ok(SysfsConfigSpace {
+            path: path.to_string(),
+            file,
+            size,
+        })
+    }
+}
+
+impl ConfigSpace for SysfsConfigSpace {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+        self.file
+            .read_exact_at(buffer, offset as u64)
+            .map_err(|err| Error::new("read", &self.path, err))
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.file
+            .write_all_at(data, offset as u64)
+            .map_err(|err| Error::new("write", &self.path, err))
+    }
+}
+
+/// Configuration space held in memory, for captured dumps and tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConfigSpace {
+    buffer: Vec<u8>,
+}
+
+impl MemoryConfigSpace {
+    pub fn new(buffer: Vec<u8>) -> MemoryConfigSpace {
+        MemoryConfigSpace { buffer }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl ConfigSpace for MemoryConfigSpace {
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+        let Some(data) = self.buffer.get(offset..(offset + buffer.len())) else {
+            return Err(Error::without_subject("read", "offset out of range"));
+        };
+
+        buffer.copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let Some(buffer) = self.buffer.get_mut(offset..(offset + data.len())) else {
+            return Err(Error::without_subject("write", "offset out of range"));
+        };
+
+        buffer.copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+pub fn field_get(value: u32, field_mask: u32) -> u32 {
+    (value & field_mask) >> field_mask.trailing_zeros()
+}
+
+pub fn field_prep(field_mask: u32, value: u32) -> u32 {
+    (value << field_mask.trailing_zeros()) & field_mask
+}
+
+pub fn read_config_u16(config_buffer: &[u8], offset: usize) -> u16 {
+    ((config_buffer[offset + 1] as u16) << 8) | (config_buffer[offset] as u16)
+}
+
+pub fn read_config_u32(config_buffer: &[u8], offset: usize) -> u32 {
+    ((read_config_u16(config_buffer, offset + 2) as u32) << 16)
+        | (read_config_u16(config_buffer, offset) as u32)
+}
+
+/// A PCI function with a snapshot of its configuration space that tracks every write.
+#[derive(Debug)]
+pub struct PciConfig {
+    pub path: String,
+    pub buffer: Vec<u8>,
+    pub writes: usize,
+    space: Box<dyn ConfigSpace>,
+}
+
+impl PciConfig {
+    /// Reads up to 4 KiB of `space`; `path` identifies the function in messages.
+    pub fn new(path: &str, space: Box<dyn ConfigSpace>) -> Result<PciConfig, Error> {
+        let mut buffer = vec![0u8; space.size().min(PCI_CFG_SPACE_EXP_SIZE)];
+
+        space
+            .read_at(0, &mut buffer)
+            .map_err(|err| err.with_subject(path))?;
+
+        Ok(PciConfig {
+            path: path.to_string(),
+            buffer,
+            writes: 0,
+            space,
+        })
+    }
+}
+
+pub fn reread_config_u16(config: &mut PciConfig, offset: usize) -> Result<u16, Error> {
+    let mut value = [0u8; 2];
+
+    config
+        .space
+        .read_at(offset, &mut value)
+        .map_err(|err| err.with_subject(&config.path))?;
+
+    config.buffer[offset..(offset + 2)].copy_from_slice(&value);
+
+    Ok(u16::from_le_bytes(value))
+}
+
+pub fn write_config_u16(config: &mut PciConfig, offset: usize, value: u16) -> Result<(), Error> {
+    config
+        .space
+        .write_at(offset, &value.to_le_bytes())
+        .map_err(|err| err.with_subject(&config.path))?;
+
+    config.buffer[offset..(offset + 2)].copy_from_slice(&value.to_le_bytes());
+    config.writes += 1;
+
+    Ok(())
+}
+
+pub fn write_config_u32(config: &mut PciConfig, offset: usize, value: u32) -> Result<(), Error> {
+    config
+        .space
+        .write_at(offset, &value.to_le_bytes())
+        .map_err(|err| err.with_subject(&config.path))?;
+
+    config.buffer[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes());
+    config.writes += 1;
+
+    Ok(())
+}
+
+/// In-memory PCI Express functions for tests, with every write logged in order.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub const TEST_PCIE_CAPABILITY: usize = 0x40;
+    pub const TEST_L1SS_CAPABILITY: usize = 0x100;
+    pub const TEST_LINK_CONTROL: usize = TEST_PCIE_CAPABILITY + PCI_EXP_LNKCTL;
+    pub const TEST_L1SS_CONTROL1: usize = TEST_L1SS_CAPABILITY + PCI_L1SS_CTL1;
+    pub const TEST_L1SS_CONTROL2: usize = TEST_L1SS_CAPABILITY + PCI_L1SS_CTL2;
+
+    /// Writes to every function of a test, in order: device path, offset and value.
+    pub type WriteLog = std::rc::Rc<std::cell::RefCell<Vec<(String, usize, u32)>>>;
+
+    #[derive(Debug)]
+    struct RecordingConfigSpace {
+        path: String,
+        space: MemoryConfigSpace,
+        /// Bits of single bytes that keep their value whatever is written.
+        hardwired: Vec<(usize, u8)>,
+        log: WriteLog,
+    }
+
+    impl ConfigSpace for RecordingConfigSpace {
+        fn size(&self) -> usize {
+            self.space.size()
+        }
+
+        fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+            self.space.read_at(offset, buffer)
+        }
+
+        fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            let mut value = [0u8; 4];
+            value[..data.len()].copy_from_slice(data);
+
+            self.log
+                .borrow_mut()
+                .push((self.path.clone(), offset, u32::from_le_bytes(value)));
+
+            let mut data = data.to_vec();
+            let mut old_data = vec![0u8; data.len()];
+            self.space.read_at(offset, &mut old_data)?;
+
+            for (byte_offset, mask) in &self.hardwired {
+                if let Some(index) = byte_offset.checked_sub(offset) {
+                    if let Some(byte) = data.get_mut(index) {
+                        *byte = (*byte & !mask) | (old_data[index] & mask);
+                    }
+                }
+            }
+
+            self.space.write_at(offset, &data)
+        }
+    }
+
+    /// Configuration space of one function: a PCI Express capability at 0x40 and, with
+    /// `l1ss`, an L1 PM Substates capability at 0x100.
+    #[derive(Debug, Clone)]
+    pub struct TestFunction {
+        pub buffer: Vec<u8>,
+        hardwired: Vec<(usize, u8)>,
+    }
+
+    impl TestFunction {
+        pub fn new(port_type: u16) -> TestFunction {
+            let mut function = TestFunction {
+                buffer: vec![0u8; PCI_CFG_SPACE_EXP_SIZE],
+                hardwired: Vec::new(),
+            };
+
+            let header_type = match port_type {
+                PCI_EXP_TYPE_ROOT_PORT | PCI_EXP_TYPE_UPSTREAM | PCI_EXP_TYPE_DOWNSTREAM => {
+                    PCI_HEADER_TYPE_BRIDGE
+                }
+                _ => PCI_HEADER_TYPE_NORMAL,
+            };
+
+            function.set_u16(PCI_VENDOR_ID, 0x8086);
+            function.buffer[PCI_HEADER_TYPE] = header_type;
+            function.buffer[PCI_CAPABILITY_LIST] = TEST_PCIE_CAPABILITY as u8;
+            function.buffer[TEST_PCIE_CAPABILITY] = PCI_CAP_ID_EXP;
+            function.set_u16(TEST_PCIE_CAPABILITY + PCI_EXP_FLAGS, 2 | (port_type << 4));
+
+            function
+        }
+
+        pub fn set_u16(&mut self, offset: usize, value: u16) {
+            self.buffer[offset..(offset + 2)].copy_from_slice(&value.to_le_bytes());
+        }
+
+        pub fn set_u32(&mut self, offset: usize, value: u32) {
+            self.buffer[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes());
+        }
+
+        pub fn device_capabilities(mut self, value: u32) -> TestFunction {
+            self.set_u32(TEST_PCIE_CAPABILITY + PCI_EXP_DEVCAP, value);
+            self
+        }
+
+        pub fn link_capabilities(mut self, value: u32) -> TestFunction {
+            self.set_u32(TEST_PCIE_CAPABILITY + PCI_EXP_LNKCAP, value);
+            self
+        }
+
+        pub fn link_control(mut self, value: u16) -> TestFunction {
+            self.set_u16(TEST_PCIE_CAPABILITY + PCI_EXP_LNKCTL, value);
+            self
+        }
+
+        pub fn link_status(mut self, value: u16) -> TestFunction {
+            self.set_u16(TEST_PCIE_CAPABILITY + PCI_EXP_LNKSTA, value);
+            self
+        }
+
+        pub fn l1ss(
+            mut self,
+            capabilities_value: u32,
+            control1_value: u32,
+            control2_value: u32,
+        ) -> TestFunction {
+            self.set_u32(TEST_L1SS_CAPABILITY, PCI_EXT_CAP_ID_L1SS as u32 | (1 << 16));
+            self.set_u32(TEST_L1SS_CAPABILITY + PCI_L1SS_CAP, capabilities_value);
+            self.set_u32(TEST_L1SS_CAPABILITY + PCI_L1SS_CTL1, control1_value);
+            self.set_u32(TEST_L1SS_CAPABILITY + PCI_L1SS_CTL2, control2_value);
+            self
+        }
+
+        /// Keeps the bits of `mask` in the 16-bit register at `offset` from ever changing.
+        pub fn hardwire_u16(mut self, offset: usize, mask: u16) -> TestFunction {
+            for (index, byte_mask) in mask.to_le_bytes().into_iter().enumerate() {
+                self.hardwired.push((offset + index, byte_mask));
+            }
+
+            self
+        }
+
+        pub fn open(self, path: &str, log: &WriteLog) -> PciConfig {
+            PciConfig::new(
+                path,
+                Box::new(RecordingConfigSpace {
+                    path: path.to_string(),
+                    space: MemoryConfigSpace::new(self.buffer),
+                    hardwired: self.hardwired,
+                    log: log.clone(),
+                }),
+            )
+            .unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    #[test]
+    fn writes_reach_space_and_cached_buffer() {
+        let log = WriteLog::default();
+        let mut config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .l1ss(PCI_L1SS_CAP_L1_PM_SS, 0, 0)
+            .open("ep", &log);
+
+        write_config_u16(&mut config, TEST_LINK_CONTROL, 0x0042).unwrap();
+        write_config_u32(&mut config, TEST_L1SS_CONTROL1, 0x0000_000f).unwrap();
+        write_config_u32(&mut config, TEST_L1SS_CONTROL2, 0x0000_0028).unwrap();
+
+        assert_eq!(config.writes, 3);
+        assert_eq!(read_config_u16(&config.buffer, TEST_LINK_CONTROL), 0x0042);
+        assert_eq!(
+            read_config_u32(&config.buffer, TEST_L1SS_CONTROL1),
+            0x0000_000f
+        );
+        assert_eq!(
+            *log.borrow(),
+            [
+                ("ep".to_string(), TEST_LINK_CONTROL, 0x0042),
+                ("ep".to_string(), TEST_L1SS_CONTROL1, 0x0000_000f),
+                ("ep".to_string(), TEST_L1SS_CONTROL2, 0x0000_0028),
+            ]
+        );
+    }
+
+    #[test]
+    fn reread_returns_what_the_device_kept() {
+        let log = WriteLog::default();
+        let mut config = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_control(PCI_EXP_LNKCTL_CCC)
+            .hardwire_u16(TEST_LINK_CONTROL, PCI_EXP_LNKCTL_CCC)
+            .open("ep", &log);
+
+        write_config_u16(&mut config, TEST_LINK_CONTROL, PCI_EXP_LNKCTL_ASPM_L1).unwrap();
+
+        assert_eq!(
+            read_config_u16(&config.buffer, TEST_LINK_CONTROL),
+            PCI_EXP_LNKCTL_ASPM_L1
+        );
+        assert_eq!(
+            reread_config_u16(&mut config, TEST_LINK_CONTROL).unwrap(),
+            PCI_EXP_LNKCTL_CCC | PCI_EXP_LNKCTL_ASPM_L1
+        );
+    }
+
+    #[test]
+    fn pci_config_reads_at_most_4k() {
+        let config = PciConfig::new(
+            "large",
+            Box::new(MemoryConfigSpace::new(vec![0; 2 * PCI_CFG_SPACE_EXP_SIZE])),
+        )
+        .unwrap();
+
+        assert_eq!(config.buffer.len(), PCI_CFG_SPACE_EXP_SIZE);
+        assert!(PciConfig::new("empty", Box::new(MemoryConfigSpace::default())).is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::regs::*;
+
+pub fn link_capabilities_aspm_support(link_capabilities_value: u32) -> u16 {
+    ((link_capabilities_value & PCI_EXP_LNKCAP_ASPMS) >> 10) as u16
+}
+
+pub fn aspm_control_names(link_control_value: u16) -> Vec<&'static str> {
+    [
+        (PCI_EXP_LNKCTL_ASPM_L0S, "L0s"),
+        (PCI_EXP_LNKCTL_ASPM_L1, "L1"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| link_control_value & bit != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+pub fn aspm_control_name(link_control_value: u16) -> String {
+    let names = aspm_control_names(link_control_value);
+
+    if names.is_empty() {
+        "disabled".to_string()
+    } else {
+        names.join(" ")
+    }
+}
+
+pub const PCI_L1SS_CTL1_NAMES: [(u32, &str); 4] = [
+    (PCI_L1SS_CTL1_ASPM_L1_1, "ASPM_L1.1"),
+    (PCI_L1SS_CTL1_ASPM_L1_2, "ASPM_L1.2"),
+    (PCI_L1SS_CTL1_PCIPM_L1_1, "PCI-PM_L1.1"),
+    (PCI_L1SS_CTL1_PCIPM_L1_2, "PCI-PM_L1.2"),
+];
+
+pub const PCI_EXP_LNKCTL_NAMES: [(u32, &str); 11] = [
+    (0x0001, "ASPM_L0s"),
+    (0x0002, "ASPM_L1"),
+    (0x0008, "RCB"),
+    (0x0010, "LnkDisable"),
+    (0x0020, "RetrainLnk"),
+    (0x0040, "CommClk"),
+    (0x0080, "ExtSynch"),
+    (0x0100, "ClockPM"),
+    (0x0200, "AutWidDis"),
+    (0x0400, "BWInt"),
+    (0x0800, "AutBWInt"),
+];
+
+pub fn bit_difference_names(old_value: u32, new_value: u32, names: &[(u32, &str)]) -> String {
+    let differences: Vec<String> = names
+        .iter()
+        .filter(|(bits, _)| old_value & bits != new_value & bits)
+        .map(|(bits, name)| {
+            if new_value & bits == 0 {
+                format!("-{}", name)
+            } else {
+                format!("+{}", name)
+            }
+        })
+        .collect();
+
+    if differences.is_empty() && old_value != new_value {
+        format!("0x{:x}", old_value ^ new_value)
+    } else {
+        differences.join(" ")
+    }
+}
+
+pub fn l1ss_control_names(l1ss_value: u32) -> Vec<&'static str> {
+    PCI_L1SS_CTL1_NAMES
+        .into_iter()
+        .filter(|(bit, _)| l1ss_value & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+pub fn l1ss_control_name(l1ss_value: u32) -> String {
+    let names = l1ss_control_names(l1ss_value);
+
+    if names.is_empty() {
+        "disabled".to_string()
+    } else {
+        names.join(" ")
+    }
+}
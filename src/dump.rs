@@ -0,0 +1,340 @@
+use crate::capability::{find_pci_exp_capability, is_pcie_downstream_port};
+use crate::config::{read_config_u16, read_config_u32, MemoryConfigSpace, PciConfig};
+use crate::error::Error;
+use crate::regs::*;
+use crate::sysfs::parse_pci_address;
+
+/// One function captured by `lspci -xxx` or `lspci -xxxx`.
+#[derive(Debug, Clone)]
+pub struct DumpedFunction {
+    pub address: String,
+    pub buffer: Vec<u8>,
+}
+
+/// Configuration space of the functions in an lspci hex dump, sorted by address. The bus
+/// numbers in the bridges stand in for the sysfs hierarchy.
+#[derive(Debug, Clone, Default)]
+pub struct LspciDump {
+    pub functions: Vec<DumpedFunction>,
+}
+
+fn parse_dump_line(line: &str) -> Option<(usize, Vec<u8>)> {
+    let (offset, bytes) = line.split_once(": ")?;
+
+    if offset.is_empty() || !offset.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let bytes = bytes
+        .split_whitespace()
+        .map(|byte| (byte.len() == 2).then(|| u8::from_str_radix(byte, 16).ok())?)
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some((usize::from_str_radix(offset, 16).ok()?, bytes))
+}
+
+/// Parses the output of `lspci -xxx` or `lspci -xxxx`, with or without `-D` and `-v`.
+pub fn parse_lspci_dump(text: &str, name: &str) -> Result<LspciDump, Error> {
+    let mut functions: Vec<DumpedFunction> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_error =
+            |message: &str| Error::new("parse", name, format!("line {}: {}", index + 1, message));
+
+        // Blank lines separate functions, indented lines are lspci -v output.
+        if line.trim().is_empty() || line.starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        if let Some((offset, bytes)) = parse_dump_line(line) {
+            let Some(function) = functions.last_mut() else {
+                return Err(line_error("hex dump before device"));
+            };
+
+            if offset != function.buffer.len() || bytes.len() != 16 {
+                return Err(line_error("unexpected hex dump line"));
+            }
+
+            function.buffer.extend(bytes);
+            continue;
+        }
+
+        let device = line.split_whitespace().next().unwrap_or_default();
+
+        let Some(address) = parse_pci_address(device) else {
+            return Err(line_error("invalid pci address"));
+        };
+
+        functions.push(DumpedFunction {
+            address,
+            buffer: Vec::new(),
+        });
+    }
+
+    for function in &functions {
+        if function.buffer.len() < PCI_CFG_SPACE_SIZE {
+            return Err(Error::new(
+                "parse",
+                name,
+                format!(
+                    "{}: only {} bytes of configuration space (use lspci -xxx or -xxxx)",
+                    function.address,
+                    function.buffer.len()
+                ),
+            ));
+        }
+    }
+
+    functions.sort_by(|a, b| a.address.cmp(&b.address));
+
+    if let Some(duplicate) = functions
+        .windows(2)
+        .find(|pair| pair[0].address == pair[1].address)
+    {
+        return Err(Error::new(
+            "parse",
+            name,
+            format!("{}: dumped more than once", duplicate[0].address),
+        ));
+    }
+
+    Ok(LspciDump { functions })
+}
+
+pub fn read_lspci_dump(path: &str) -> Result<LspciDump, Error> {
+    let text = std::fs::read_to_string(path).map_err(|err| Error::new("read", path, err))?;
+
+    parse_lspci_dump(&text, path)
+}
+
+/// Formats `buffer` like `lspci -n -xxxx`: the device line, then 16 bytes per line and a
+/// blank line. Domain 0000 is left out of the address as lspci does without `-D`.
+pub fn format_lspci_dump(address: &str, buffer: &[u8]) -> String {
+    let address = address.strip_prefix("0000:").unwrap_or(address);
+    let mut text = address.to_string();
+
+    if buffer.len() >= PCI_CLASS_REVISION + 4 {
+        let class_revision = read_config_u32(buffer, PCI_CLASS_REVISION);
+
+        text.push_str(&format!(
+            " {:04x}: {:04x}:{:04x}",
+            class_revision >> 16,
+            read_config_u16(buffer, PCI_VENDOR_ID),
+            read_config_u16(buffer, PCI_DEVICE_ID)
+        ));
+
+        if class_revision & 0xff != 0 {
+            text.push_str(&format!(" (rev {:02x})", class_revision & 0xff));
+        }
+    }
+
+    text.push('\n');
+
+    for (index, line) in buffer.chunks(16).enumerate() {
+        text.push_str(&format!("{:02x}:", index * 16));
+
+        for byte in line {
+            text.push_str(&format!(" {:02x}", byte));
+        }
+
+        text.push('\n');
+    }
+
+    text.push('\n');
+
+    text
+}
+
+fn address_domain_bus(address: &str) -> Option<(&str, u8)> {
+    let mut fields = address.split(':');
+    let domain = fields.next()?;
+    let bus = u8::from_str_radix(fields.next()?, 16).ok()?;
+
+    Some((domain, bus))
+}
+
+impl LspciDump {
+    pub fn addresses(&self) -> Vec<String> {
+        self.functions
+            .iter()
+            .map(|function| function.address.clone())
+            .collect()
+    }
+
+    /// Finds a function by any form `parse_pci_address` accepts.
+    pub fn find(&self, device: &str) -> Result<&DumpedFunction, Error> {
+        let Some(address) = parse_pci_address(device) else {
+            return Err(Error::new("syntax", device, "invalid pci address"));
+        };
+
+        self.functions
+            .iter()
+            .find(|function| function.address == address)
+            .ok_or_else(|| Error::new("error", &address, "not in dump"))
+    }
+
+    /// Opens a copy of the dumped configuration space; writes only change the copy.
+    pub fn open(&self, device: &str) -> Result<PciConfig, Error> {
+        let function = self.find(device)?;
+
+        PciConfig::new(
+            &function.address,
+            Box::new(MemoryConfigSpace::new(function.buffer.clone())),
+        )
+    }
+
+    /// The bridge whose secondary bus holds `address`, the dump's stand-in for the sysfs parent.
+    pub fn find_upstream_address(&self, address: &str) -> Option<String> {
+        let (domain, bus) = address_domain_bus(address)?;
+
+        self.functions
+            .iter()
+            .find(|function| {
+                // Unconfigured bridges leave the secondary bus at zero.
+                address_domain_bus(&function.address).is_some_and(|(bridge_domain, bridge_bus)| {
+                    bridge_domain == domain && bridge_bus < bus
+                }) && function.buffer[PCI_HEADER_TYPE] & PCI_HEADER_TYPE_MASK
+                    == PCI_HEADER_TYPE_BRIDGE
+                    && function.buffer[PCI_SECONDARY_BUS] == bus
+            })
+            .map(|function| function.address.clone())
+    }
+
+    /// Like `open_pci_link`: the downstream port above `device`, if dumped, then `device`.
+    pub fn open_link(&self, device: &str) -> Result<Vec<PciConfig>, Error> {
+        let config = self.open(device)?;
+        let mut link = Vec::new();
+
+        if let Some(upstream_address) = self.find_upstream_address(&config.path) {
+            let upstream_config = self.open(&upstream_address)?;
+
+            if is_pcie_downstream_port(&upstream_config.buffer) {
+                link.push(upstream_config);
+            }
+        }
+
+        link.push(config);
+
+        Ok(link)
+    }
+
+    /// Like `open_pci_path_to_root`, nearest link first.
+    pub fn open_path_to_root(&self, device: &str) -> Result<Vec<(PciConfig, PciConfig)>, Error> {
+        let mut links = Vec::new();
+        let mut downstream_address = self.find(device)?.address.clone();
+
+        while let Some(upstream_address) = self.find_upstream_address(&downstream_address) {
+            let upstream_config = self.open(&upstream_address)?;
+
+            if !is_pcie_downstream_port(&upstream_config.buffer) {
+                break;
+            }
+
+            links.push((upstream_config, self.open(&downstream_address)?));
+
+            let Some(switch_upstream_address) = self.find_upstream_address(&upstream_address)
+            else {
+                break;
+            };
+
+            downstream_address = switch_upstream_address;
+        }
+
+        Ok(links)
+    }
+
+    /// Like `open_pci_hierarchy`, from the root port down to `device`.
+    pub fn open_hierarchy(&self, device: &str) -> Result<Vec<PciConfig>, Error> {
+        let mut hierarchy = vec![self.open(device)?];
+        let mut address = hierarchy[0].path.clone();
+
+        while let Some(upstream_address) = self.find_upstream_address(&address) {
+            let upstream_config = self.open(&upstream_address)?;
+
+            if find_pci_exp_capability(&upstream_config.buffer).is_err() {
+                break;
+            }
+
+            hierarchy.push(upstream_config);
+            address = upstream_address;
+        }
+
+        hierarchy.reverse();
+
+        Ok(hierarchy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function_buffer(size: usize, seed: u8) -> Vec<u8> {
+        let mut buffer: Vec<u8> = (0..size)
+            .map(|offset| (offset as u8).wrapping_mul(7).wrapping_add(seed))
+            .collect();
+
+        buffer[PCI_HEADER_TYPE] = 0;
+
+        buffer
+    }
+
+    #[test]
+    fn format_parse_round_trip() {
+        let functions = [
+            ("0000:00:1c.0", function_buffer(PCI_CFG_SPACE_EXP_SIZE, 1)),
+            ("0000:03:00.0", function_buffer(PCI_CFG_SPACE_SIZE, 2)),
+            ("0001:00:00.0", function_buffer(PCI_CFG_SPACE_SIZE, 3)),
+        ];
+
+        let text: String = functions
+            .iter()
+            .rev()
+            .map(|(address, buffer)| format_lspci_dump(address, buffer))
+            .collect();
+
+        let dump = parse_lspci_dump(&text, "dump").unwrap();
+
+        assert_eq!(dump.functions.len(), functions.len());
+
+        for (function, (address, buffer)) in dump.functions.iter().zip(&functions) {
+            assert_eq!(function.address, *address);
+            assert_eq!(function.buffer, *buffer);
+        }
+    }
+
+    #[test]
+    fn parse_lspci_dump_skips_verbose_lines() {
+        let mut text = format_lspci_dump("03:00.0", &function_buffer(PCI_CFG_SPACE_SIZE, 4));
+        text.insert_str(
+            text.find('\n').unwrap() + 1,
+            "\tSubsystem: Device 0000\n\tFlags: fast devsel\n",
+        );
+
+        let dump = parse_lspci_dump(&text, "dump").unwrap();
+
+        assert_eq!(dump.addresses(), ["0000:03:00.0"]);
+        assert_eq!(dump.functions[0].buffer.len(), PCI_CFG_SPACE_SIZE);
+    }
+
+    #[test]
+    fn parse_lspci_dump_rejects_incomplete_dumps() {
+        let buffer = function_buffer(PCI_CFG_SPACE_SIZE, 5);
+        let text = format_lspci_dump("03:00.0", &buffer);
+
+        let short_text = format_lspci_dump("03:00.0", &buffer[..64]);
+        assert!(parse_lspci_dump(&short_text, "dump").is_err());
+
+        let duplicate_text = format!("{}{}", text, text);
+        assert!(parse_lspci_dump(&duplicate_text, "dump").is_err());
+
+        let headless_text = text.split_once('\n').unwrap().1;
+        assert!(parse_lspci_dump(headless_text, "dump").is_err());
+
+        let gap_text = text.replacen("10:", "20:", 1);
+        assert!(parse_lspci_dump(&gap_text, "dump").is_err());
+
+        let address_text = text.replacen("03:00.0", "03:00.9", 1);
+        assert!(parse_lspci_dump(&address_text, "dump").is_err());
+    }
+}
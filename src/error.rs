@@ -0,0 +1,38 @@
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub context: &'static str,
+    pub subject: Option<String>,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(context: &'static str, subject: &str, message: impl std::fmt::Display) -> Error {
+        Error {
+            context,
+            subject: Some(subject.to_string()),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn without_subject(context: &'static str, message: impl std::fmt::Display) -> Error {
+        Error {
+            context,
+            subject: None,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn with_subject(mut self, subject: &str) -> Error {
+        self.subject.get_or_insert_with(|| subject.to_string());
+        self
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subject {
+            Some(subject) => write!(f, "{}: {}: {}", self.context, subject, self.message),
+            None => write!(f, "{}: {}", self.context, self.message),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::capability::find_pci_l1ss;
+use crate::config::{field_get, field_prep, read_config_u32, PciConfig};
+use crate::error::Error;
+use crate::regs::*;
+
+pub fn l12_power_on_time(scale: u32, value: u32) -> Option<u32> {
+    match scale {
+        0 => Some(value * 2),
+        1 => Some(value * 10),
+        2 => Some(value * 100),
+        _ => None,
+    }
+}
+
+pub fn encode_l12_threshold(threshold_us: u32) -> (u32, u32) {
+    let threshold_ns = threshold_us as u64 * 1000;
+    let value_max = field_get(
+        PCI_L1SS_CTL1_LTR_L12_TH_VALUE,
+        PCI_L1SS_CTL1_LTR_L12_TH_VALUE,
+    ) as u64;
+
+    for scale in 0..6 {
+        let unit_ns = 1u64 << (5 * scale);
+
+        if threshold_ns <= unit_ns * value_max {
+            return (scale, threshold_ns.div_ceil(unit_ns) as u32);
+        }
+    }
+
+    (5, value_max as u32)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct L12Timing {
+    pub upstream_l1ss: usize,
+    pub downstream_l1ss: usize,
+    pub l1ss_control1_timing_value: u32,
+    pub l1ss_control2_value: u32,
+}
+
+pub fn calc_l12_timing(upstream: &PciConfig, downstream: &PciConfig) -> Result<L12Timing, Error> {
+    let mut l1ss_ranges = Vec::new();
+
+    for config in [upstream, downstream] {
+        let Some(l1ss_range) =
+            find_pci_l1ss(&config.buffer).map_err(|err| err.with_subject(&config.path))?
+        else {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "unable to find l1 pm substates capability",
+            ));
+        };
+
+        let l1ss_capabilities_value =
+            read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CAP);
+
+        if l1ss_capabilities_value & (PCI_L1SS_CAP_ASPM_L1_2 | PCI_L1SS_CAP_PCIPM_L1_2) == 0 {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                format!(
+                    "l1.2 not supported by l1 pm substates capabilities 0x{:08x}",
+                    l1ss_capabilities_value
+                ),
+            ));
+        }
+
+        let power_on_scale = field_get(l1ss_capabilities_value, PCI_L1SS_CAP_P_PWR_ON_SCALE);
+        let power_on_value = field_get(l1ss_capabilities_value, PCI_L1SS_CAP_P_PWR_ON_VALUE);
+
+        let Some(power_on_time) = l12_power_on_time(power_on_scale, power_on_value) else {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                format!("invalid t_power_on scale {}", power_on_scale),
+            ));
+        };
+
+        l1ss_ranges.push((
+            l1ss_range.start,
+            l1ss_capabilities_value,
+            (power_on_scale, power_on_value, power_on_time),
+        ));
+    }
+
+    let (upstream_l1ss, upstream_l1ss_capabilities_value, upstream_power_on) = l1ss_ranges[0];
+    let (downstream_l1ss, downstream_l1ss_capabilities_value, downstream_power_on) = l1ss_ranges[1];
+
+    let common_mode_restore_time = std::cmp::max(
+        field_get(
+            upstream_l1ss_capabilities_value,
+            PCI_L1SS_CAP_CM_RESTORE_TIME,
+        ),
+        field_get(
+            downstream_l1ss_capabilities_value,
+            PCI_L1SS_CAP_CM_RESTORE_TIME,
+        ),
+    );
+
+    let (power_on_scale, power_on_value, power_on_time) =
+        if upstream_power_on.2 > downstream_power_on.2 {
+            upstream_power_on
+        } else {
+            downstream_power_on
+        };
+
+    // T(POWER_OFF) is at most 2us and T(L1.2) is at least 4us (PCIe r4.0, sec 5.5.3.3.1).
+    let (threshold_scale, threshold_value) =
+        encode_l12_threshold(2 + 4 + common_mode_restore_time + power_on_time);
+
+    let l1ss_control2_value = field_prep(PCI_L1SS_CTL2_T_PWR_ON_SCALE, power_on_scale)
+        | field_prep(PCI_L1SS_CTL2_T_PWR_ON_VALUE, power_on_value);
+    let l1ss_control1_timing_value =
+        field_prep(PCI_L1SS_CTL1_CM_RESTORE_TIME, common_mode_restore_time)
+            | field_prep(PCI_L1SS_CTL1_LTR_L12_TH_VALUE, threshold_value)
+            | field_prep(PCI_L1SS_CTL1_LTR_L12_TH_SCALE, threshold_scale);
+
+    Ok(L12Timing {
+        upstream_l1ss,
+        downstream_l1ss,
+        l1ss_control1_timing_value,
+        l1ss_control2_value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MemoryConfigSpace;
+
+    fn l1ss_config(path: &str, l1ss_capabilities_value: u32) -> PciConfig {
+        let mut buffer = vec![0u8; PCI_CFG_SPACE_EXP_SIZE];
+        let header = PCI_EXT_CAP_ID_L1SS as u32 | (1 << 16);
+
+        buffer[0x100..0x104].copy_from_slice(&header.to_le_bytes());
+        buffer[(0x100 + PCI_L1SS_CAP)..(0x100 + PCI_L1SS_CAP + 4)]
+            .copy_from_slice(&l1ss_capabilities_value.to_le_bytes());
+
+        PciConfig::new(path, Box::new(MemoryConfigSpace::new(buffer))).unwrap()
+    }
+
+    #[test]
+    fn encode_l12_threshold_picks_smallest_scale() {
+        assert_eq!(encode_l12_threshold(0), (0, 0));
+        assert_eq!(encode_l12_threshold(1), (0, 1000));
+        assert_eq!(encode_l12_threshold(2), (1, 63));
+        assert_eq!(encode_l12_threshold(56), (2, 55));
+        assert_eq!(encode_l12_threshold(u32::MAX), (5, 0x3ff));
+    }
+
+    #[test]
+    fn calc_l12_timing_uses_slowest_port() {
+        let upstream = l1ss_config(
+            "upstream",
+            PCI_L1SS_CAP_ASPM_L1_2
+                | field_prep(PCI_L1SS_CAP_CM_RESTORE_TIME, 10)
+                | field_prep(PCI_L1SS_CAP_P_PWR_ON_SCALE, 1)
+                | field_prep(PCI_L1SS_CAP_P_PWR_ON_VALUE, 3),
+        );
+        let downstream = l1ss_config(
+            "downstream",
+            PCI_L1SS_CAP_ASPM_L1_2
+                | field_prep(PCI_L1SS_CAP_CM_RESTORE_TIME, 20)
+                | field_prep(PCI_L1SS_CAP_P_PWR_ON_SCALE, 0)
+                | field_prep(PCI_L1SS_CAP_P_PWR_ON_VALUE, 5),
+        );
+
+        let timing = calc_l12_timing(&upstream, &downstream).unwrap();
+
+        // 2us + 4us + 20us common mode restore + 30us power on = 56us.
+        assert_eq!(timing.upstream_l1ss, 0x100);
+        assert_eq!(timing.downstream_l1ss, 0x100);
+        assert_eq!(timing.l1ss_control1_timing_value, 0x4037_1400);
+        assert_eq!(timing.l1ss_control2_value, 0x19);
+    }
+
+    #[test]
+    fn calc_l12_timing_requires_l12_support() {
+        let upstream = l1ss_config("upstream", PCI_L1SS_CAP_ASPM_L1_2);
+        let downstream = l1ss_config("downstream", PCI_L1SS_CAP_L1_PM_SS);
+
+        assert!(calc_l12_timing(&upstream, &downstream).is_err());
+
+        let downstream = l1ss_config(
+            "downstream",
+            PCI_L1SS_CAP_ASPM_L1_2 | field_prep(PCI_L1SS_CAP_P_PWR_ON_SCALE, 3),
+        );
+
+        assert!(calc_l12_timing(&upstream, &downstream).is_err());
+    }
+}
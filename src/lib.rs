@@ -0,0 +1,29 @@
+//! PCI Express ASPM inspection and configuration through PCI configuration space.
+//!
+//! [`apply_link`] changes a link with every check the `aspmctl` command makes; the functions
+//! in [`aspm`] write registers without any.
+
+pub mod acpi;
+pub mod apply;
+pub mod aspm;
+pub mod capability;
+pub mod config;
+pub mod decode;
+pub mod dump;
+pub mod error;
+pub mod l1ss;
+pub mod ltr;
+pub mod pcie;
+pub mod policy;
+pub mod regs;
+pub mod source;
+pub mod state;
+pub mod sysfs;
+
+pub use apply::{apply_link, ApplyOptions, ApplyReporter, Backend};
+pub use aspm::{apply_aspm_link, plan_aspm, AspmPlan, AspmRequest, LatencyViolation};
+pub use capability::find_pci_capability;
+pub use config::{ConfigSpace, MemoryConfigSpace, PciConfig, SysfsConfigSpace};
+pub use error::Error;
+pub use pcie::{decode_pcie_capability, PcieCapability};
+pub use source::ConfigSource;
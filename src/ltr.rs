@@ -0,0 +1,248 @@
+use crate::capability::{find_pci_exp_capability, find_pci_ltr};
+use crate::config::{
+    field_get, field_prep, read_config_u16, read_config_u32, write_config_u16, PciConfig,
+};
+use crate::error::Error;
+use crate::regs::*;
+
+/// Encodes a latency as an LTR value and scale, rounding up to the next representable latency.
+pub fn encode_ltr_latency(latency_ns: u64) -> u16 {
+    let value_max = PCI_LTR_VALUE_MASK as u64;
+
+    for scale in 0..6 {
+        let unit_ns = 1u64 << (5 * scale);
+
+        if latency_ns <= unit_ns * value_max {
+            return (field_prep(PCI_LTR_SCALE_MASK as u32, scale)
+                | latency_ns.div_ceil(unit_ns) as u32) as u16;
+        }
+    }
+
+    PCI_LTR_VALUE_MASK | field_prep(PCI_LTR_SCALE_MASK as u32, 5) as u16
+}
+
+/// Latency in nanoseconds of an LTR value and scale, `None` for the reserved scales.
+pub fn ltr_latency_ns(value: u16) -> Option<u64> {
+    let scale = field_get(value as u32, PCI_LTR_SCALE_MASK as u32);
+
+    (scale < 6).then(|| (value & PCI_LTR_VALUE_MASK) as u64 * (1u64 << (5 * scale)))
+}
+
+/// LTR settings to change; `None` leaves the setting as it is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LtrRequest {
+    pub enable: Option<bool>,
+    pub max_snoop_latency: Option<u16>,
+    pub max_no_snoop_latency: Option<u16>,
+}
+
+/// Register values read from one function and the values `apply_ltr_hierarchy` will write.
+#[derive(Debug)]
+pub struct LtrPlan {
+    pub device_control2_offset: usize,
+    pub device_control2_old_value: u16,
+    pub device_control2_new_value: u16,
+    pub ltr_offset: Option<usize>,
+    pub max_snoop_latency_old_value: u16,
+    pub max_snoop_latency_new_value: u16,
+    pub max_no_snoop_latency_old_value: u16,
+    pub max_no_snoop_latency_new_value: u16,
+}
+
+pub fn ltr_supported(config_buffer: &[u8]) -> Result<bool, Error> {
+    let capability_range = find_pci_exp_capability(config_buffer)?;
+
+    // Device Capabilities 2 only exists in version 2 of the capability.
+    if read_config_u16(config_buffer, capability_range.start + PCI_EXP_FLAGS) & PCI_EXP_FLAGS_VERS
+        < 2
+    {
+        return Ok(false);
+    }
+
+    Ok(
+        read_config_u32(config_buffer, capability_range.start + PCI_EXP_DEVCAP2)
+            & PCI_EXP_DEVCAP2_LTR
+            != 0,
+    )
+}
+
+pub fn plan_ltr(request: &LtrRequest, config: &PciConfig) -> Result<LtrPlan, Error> {
+    let capability_range =
+        find_pci_exp_capability(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+    let supported = ltr_supported(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+
+    if request.enable == Some(true) && !supported {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            "latency tolerance reporting not supported",
+        ));
+    }
+
+    let device_control2_offset = capability_range.start + PCI_EXP_DEVCTL2;
+    let device_control2_old_value = if supported {
+        read_config_u16(&config.buffer, device_control2_offset)
+    } else {
+        0
+    };
+
+    let device_control2_new_value = match request.enable {
+        Some(true) => device_control2_old_value | PCI_EXP_DEVCTL2_LTR_EN,
+        Some(false) => device_control2_old_value & !PCI_EXP_DEVCTL2_LTR_EN,
+        None => device_control2_old_value,
+    };
+
+    let mut plan = LtrPlan {
+        device_control2_offset,
+        device_control2_old_value,
+        device_control2_new_value,
+        ltr_offset: None,
+        max_snoop_latency_old_value: 0,
+        max_snoop_latency_new_value: 0,
+        max_no_snoop_latency_old_value: 0,
+        max_no_snoop_latency_new_value: 0,
+    };
+
+    let ltr_range = find_pci_ltr(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+
+    if let Some(ltr_range) = ltr_range {
+        let max_snoop_latency_value =
+            read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_SNOOP_LAT);
+        let max_no_snoop_latency_value =
+            read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_NOSNOOP_LAT);
+
+        plan.ltr_offset = Some(ltr_range.start);
+        plan.max_snoop_latency_old_value = max_snoop_latency_value;
+        plan.max_snoop_latency_new_value =
+            request.max_snoop_latency.unwrap_or(max_snoop_latency_value);
+        plan.max_no_snoop_latency_old_value = max_no_snoop_latency_value;
+        plan.max_no_snoop_latency_new_value = request
+            .max_no_snoop_latency
+            .unwrap_or(max_no_snoop_latency_value);
+    } else if request.max_snoop_latency.is_some() || request.max_no_snoop_latency.is_some() {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            "unable to find latency tolerance reporting capability",
+        ));
+    }
+
+    Ok(plan)
+}
+
+/// Plans `request` for the last function of `hierarchy`, which lists the functions from the
+/// root port down. Enabling also enables every port above it; other settings stay local.
+pub fn plan_ltr_hierarchy(
+    request: &LtrRequest,
+    hierarchy: &[PciConfig],
+) -> Result<Vec<LtrPlan>, Error> {
+    let Some((device_config, port_configs)) = hierarchy.split_last() else {
+        return Ok(Vec::new());
+    };
+
+    // Disabling a port would take LTR away from its other children as well.
+    let port_request = LtrRequest {
+        enable: request.enable.filter(|enable| *enable),
+        ..LtrRequest::default()
+    };
+
+    let mut plans = port_configs
+        .iter()
+        .map(|config| plan_ltr(&port_request, config))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    plans.push(plan_ltr(request, device_config)?);
+
+    Ok(plans)
+}
+
+pub fn apply_ltr_hierarchy(hierarchy: &mut [PciConfig], plans: &[LtrPlan]) -> Result<(), Error> {
+    // The limits must be in place before the function may start sending LTR messages.
+    for (config, plan) in hierarchy.iter_mut().zip(plans) {
+        if let Some(ltr_offset) = plan.ltr_offset {
+            if plan.max_snoop_latency_new_value != plan.max_snoop_latency_old_value {
+                write_config_u16(
+                    config,
+                    ltr_offset + PCI_LTR_MAX_SNOOP_LAT,
+                    plan.max_snoop_latency_new_value,
+                )?;
+            }
+
+            if plan.max_no_snoop_latency_new_value != plan.max_no_snoop_latency_old_value {
+                write_config_u16(
+                    config,
+                    ltr_offset + PCI_LTR_MAX_NOSNOOP_LAT,
+                    plan.max_no_snoop_latency_new_value,
+                )?;
+            }
+        }
+    }
+
+    // Disable below before above, enable from the root port down (PCIe r4.0, sec 6.18).
+    for (config, plan) in hierarchy.iter_mut().zip(plans).rev() {
+        if plan.device_control2_old_value & !plan.device_control2_new_value & PCI_EXP_DEVCTL2_LTR_EN
+            != 0
+        {
+            write_config_u16(
+                config,
+                plan.device_control2_offset,
+                plan.device_control2_new_value,
+            )?;
+        }
+    }
+
+    for (config, plan) in hierarchy.iter_mut().zip(plans) {
+        if !plan.device_control2_old_value & plan.device_control2_new_value & PCI_EXP_DEVCTL2_LTR_EN
+            != 0
+        {
+            write_config_u16(
+                config,
+                plan.device_control2_offset,
+                plan.device_control2_new_value,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_ltr_latency_rounds_up() {
+        assert_eq!(encode_ltr_latency(0), 0);
+        assert_eq!(encode_ltr_latency(1023), 0x03ff);
+        assert_eq!(encode_ltr_latency(1024), 0x0420);
+        assert_eq!(encode_ltr_latency(3000), 0x045e);
+        assert_eq!(ltr_latency_ns(0x045e), Some(3008));
+    }
+
+    #[test]
+    fn encode_ltr_latency_saturates() {
+        assert_eq!(encode_ltr_latency(u64::MAX), 0x17ff);
+        assert_eq!(ltr_latency_ns(0x17ff), Some(0x3ff << 25));
+    }
+
+    #[test]
+    fn ltr_latency_ns_rejects_reserved_scales() {
+        assert_eq!(ltr_latency_ns(0x1801), None);
+        assert_eq!(ltr_latency_ns(0x1c01), None);
+    }
+
+    #[test]
+    fn ltr_latency_round_trip() {
+        for latency_ns in [1, 31, 32, 1000, 32_736, 32_737, 1_000_000, 3_000_000_000] {
+            let latency = ltr_latency_ns(encode_ltr_latency(latency_ns)).unwrap();
+
+            assert!(latency >= latency_ns, "{}", latency_ns);
+            // One unit of the scale chosen, which is at most 32 times finer than needed.
+            assert!(
+                latency - latency_ns < 32 * latency_ns.div_ceil(1023),
+                "{}",
+                latency_ns
+            );
+        }
+    }
+}
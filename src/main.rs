@@ -1,80 +1,429 @@
-use std::io::Read;
-use std::os::unix::fs::FileExt;
 use std::process::ExitCode;
 
-const PCI_CAPABILITY_LIST: usize = 0x34;
-const PCI_CAP_ID_EXP: u8 = 0x10;
-const PCI_CAP_ID_EXP_LEN: usize = 0x3c;
-const PCI_EXP_LNKCTL: usize = 0x10;
-const PCI_EXP_LNKCTL_ASPM_L0S: u16 = 0x0001;
-const PCI_EXP_LNKCTL_ASPM_L1: u16 = 0x0002;
+use aspmctl::acpi::SYSFS_ACPI_TABLES;
+use aspmctl::apply::{
+    apply_link, check_firmware_aspm, link_skip_reason, restore_link, restore_ltr, ApplyOptions,
+    ApplyReporter, AspmExecution, Backend,
+};
+use aspmctl::aspm::{plan_aspm, AspmPlan, AspmRequest};
+use aspmctl::capability::{
+    find_pci_capability, find_pci_exp_capability, find_pci_exp_link_capabilities,
+    find_pci_exp_link_control, find_pci_exp_link_status, find_pci_l1ss, find_pci_ltr,
+    is_pcie_downstream_port, pci_ext_capabilities,
+};
+use aspmctl::config::{read_config_u16, read_config_u32, PciConfig};
+use aspmctl::decode::{
+    aspm_control_name, aspm_control_names, bit_difference_names, l1ss_control_name,
+    l1ss_control_names, link_capabilities_aspm_support, PCI_EXP_LNKCTL_NAMES, PCI_L1SS_CTL1_NAMES,
+};
+use aspmctl::dump::{format_lspci_dump, read_lspci_dump};
+use aspmctl::error::Error;
+use aspmctl::ltr::{encode_ltr_latency, ltr_latency_ns, ltr_supported, LtrPlan, LtrRequest};
+use aspmctl::pcie::{decode_pcie_capability, PcieCapability};
+use aspmctl::policy::{
+    aspm_policy_conflicts, kernel_aspm_disabled, parse_aspm_policy, read_aspm_policy,
+    write_aspm_policy, AspmPolicy, PROC_CMDLINE, SYSFS_PCIE_ASPM_POLICY,
+};
+use aspmctl::regs::*;
+use aspmctl::source::ConfigSource;
+use aspmctl::state::{
+    plan_ltr_restore, plan_restore, read_state_file, write_state_file, SavedState,
+    DEFAULT_STATE_FILE,
+};
+use aspmctl::sysfs::{
+    find_upstream_config_path, open_pci_config, open_pci_link, parse_pci_address, pci_device_name,
+    resolve_config_path, SYSFS_PCI_DEVICES,
+};
 
-fn find_pci_capability(
-    config_buffer: &[u8],
-    target_capability_id: u8,
-    target_capability_length: usize,
-) -> Option<std::ops::Range<usize>> {
-    let mut capability_pointer = *config_buffer.get(PCI_CAPABILITY_LIST)? as usize;
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
 
-    loop {
-        let capability_id = *config_buffer.get(capability_pointer)?;
-        let next_capability_pointer = *config_buffer.get(capability_pointer + 1)? as usize;
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
 
-        if next_capability_pointer != 0 && next_capability_pointer < capability_pointer + 2 {
-            eprintln!("error: next capability pointer invalid");
-            return None;
-        }
+impl From<u64> for Json {
+    fn from(value: u64) -> Json {
+        Json::Number(value)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Json {
+        Json::Number(value as u64)
+    }
+}
+
+impl From<u16> for Json {
+    fn from(value: u16) -> Json {
+        Json::Number(value as u64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        Json::Number(value as u64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(value: Vec<T>) -> Json {
+        Json::Array(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<&Error> for Json {
+    fn from(error: &Error) -> Json {
+        Json::Object(vec![
+            ("context", error.context.into()),
+            ("subject", error.subject.clone().into()),
+            ("message", error.message.clone().into()),
+        ])
+    }
+}
 
-        if capability_id == target_capability_id {
-            if (next_capability_pointer >= capability_pointer
-                && target_capability_length > next_capability_pointer - capability_pointer)
-                || (target_capability_length > config_buffer.len() - capability_pointer)
-            {
-                eprintln!("error: capability length overflow");
-                return None;
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => {
+                write!(f, "\"")?;
+
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+
+                write!(f, "\"")
             }
+            Json::Array(values) => {
+                write!(f, "[")?;
 
-            return Some((capability_pointer)..(capability_pointer + target_capability_length));
-        }
+                for (index, value) in values.iter().enumerate() {
+                    if index != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
 
-        if next_capability_pointer > capability_pointer {
-            capability_pointer = next_capability_pointer;
-        } else {
-            return None;
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::from(*key), value)?;
+                }
+
+                write!(f, "}}")
+            }
         }
     }
 }
 
-fn find_pci_exp_link_control(config_buffer: &[u8]) -> Option<std::ops::Range<usize>> {
-    let Some(capability_range) = find_pci_capability(&config_buffer, PCI_CAP_ID_EXP, PCI_CAP_ID_EXP_LEN) else {
-        eprintln!("error: unable to find pci express capability structure");
-        return None;
-    };
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Apply,
+    Status,
+    Restore,
+    Watch,
+    Generate,
+    Dump,
+}
 
-    Some((capability_range.start + PCI_EXP_LNKCTL)..(capability_range.start + PCI_EXP_LNKCTL + 2))
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Format {
+    #[default]
+    Text,
+    Json,
 }
 
+const DEFAULT_GENERATE_BINARY: &str = "/usr/sbin/aspmctl";
+const UDEV_RULES_FILE: &str = "90-aspmctl.rules";
+const SYSTEMD_UNIT_FILE: &str = "aspmctl.service";
+const GENERATED_STATE_FILE: &str = "/run/aspmctl/state";
+
+const DEFAULT_WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Exit status when writes went through but the hardware did not keep the new values.
+const EXIT_VERIFY_FAILED: u8 = 2;
+
 #[derive(Debug)]
 struct Args {
-    mask: u16,
-    flags: u16,
-    path: String,
+    mode: Mode,
+    format: Format,
+    options: ApplyOptions,
+    set_policy: Option<AspmPolicy>,
+    verbose: bool,
+    interval: std::time::Duration,
+    binary: String,
+    output_dir: Option<String>,
+    all: bool,
+    device: Option<String>,
+    selectors: Vec<String>,
+    source: ConfigSource,
+}
+
+const LINK_CONTROL_OPTION_NAMES: [(u16, &str); 3] = [
+    (PCI_EXP_LNKCTL_ASPM_L0S, "l0s"),
+    (PCI_EXP_LNKCTL_ASPM_L1, "l1"),
+    (PCI_EXP_LNKCTL_CLKREQ_EN, "clkpm"),
+];
+
+const L1SS_OPTION_NAMES: [(u32, &str); 4] = [
+    (PCI_L1SS_CTL1_ASPM_L1_1, "l1.1"),
+    (PCI_L1SS_CTL1_ASPM_L1_2, "l1.2"),
+    (PCI_L1SS_CTL1_PCIPM_L1_1, "pcipm-l1.1"),
+    (PCI_L1SS_CTL1_PCIPM_L1_2, "pcipm-l1.2"),
+];
+
+fn l1ss_option_bit(arg: &str, prefix: &str) -> Option<u32> {
+    let name = arg.strip_prefix(prefix)?;
+
+    L1SS_OPTION_NAMES
+        .iter()
+        .find(|(_, option_name)| *option_name == name)
+        .map(|(bit, _)| *bit)
+}
+
+fn parse_ltr_latency(option: &str, value: &str) -> Result<u16, Error> {
+    let latency_ns: u64 = value
+        .parse()
+        .map_err(|_| Error::new("syntax", option, "invalid latency"))?;
+
+    Ok(encode_ltr_latency(latency_ns))
+}
+
+fn parse_format(value: &str) -> Result<Format, Error> {
+    match value {
+        "text" => Ok(Format::Text),
+        "json" => Ok(Format::Json),
+        _ => Err(Error::new("syntax", value, "unrecognized format")),
+    }
+}
+
+fn parse_interval(value: &str) -> Result<std::time::Duration, Error> {
+    match value.parse() {
+        Ok(seconds) if seconds > 0 => Ok(std::time::Duration::from_secs(seconds)),
+        _ => Err(Error::new("syntax", value, "invalid interval")),
+    }
+}
+
+fn parse_policy(value: &str) -> Result<AspmPolicy, Error> {
+    parse_aspm_policy(value).ok_or_else(|| Error::new("syntax", value, "unrecognized policy"))
+}
+
+fn parse_backend(value: &str) -> Result<Backend, Error> {
+    match value {
+        "auto" => Ok(Backend::Auto),
+        "sysfs-link" => Ok(Backend::SysfsLink),
+        "raw" => Ok(Backend::Raw),
+        _ => Err(Error::new("syntax", value, "unrecognized backend")),
+    }
 }
 
-fn parse_args() -> Option<Args> {
-    let mut path: Option<String> = None;
+fn requested_format() -> Format {
+    let mut args = std::env::args().skip(1);
+    let mut format = Format::Text;
+
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--format=") {
+            Some(value) => Some(value.to_string()),
+            None if arg == "--format" => args.next(),
+            None => None,
+        };
+
+        if let Some(Ok(value)) = value.map(|value| parse_format(&value)) {
+            format = value;
+        }
+    }
+
+    format
+}
+
+fn parse_args() -> Result<Args, Error> {
+    let mut mode = Mode::Apply;
+    let mut format = Format::Text;
+    let mut backend = Backend::Auto;
+    let mut device: Option<String> = None;
     let mut flags = 0;
     let mut mask = 0;
+    let mut l1ss_flags = 0;
+    let mut l1ss_mask = 0;
+    let mut ltr = LtrRequest::default();
+    let mut set_policy = None;
+    let mut program_l12_timing = false;
+    let mut common_clock = false;
+    let mut dry_run = false;
+    let mut force = false;
+    let mut verbose = false;
+    let mut state_file = DEFAULT_STATE_FILE.to_string();
+    let mut interval = None;
+    let mut binary = None;
+    let mut output_dir = None;
+    let mut all = false;
+    let mut selectors = Vec::new();
+    let mut lspci_dump = None;
+    let mut ignore_firmware = false;
+    let mut acpi_tables = None;
 
-    let mut args = std::env::args();
+    let mut args = std::env::args().peekable();
     let _program = args.next();
 
-    loop {
-        let Some(arg) = args.next() else {
-            break;
-        };
+    if let Some("status") = args.peek().map(String::as_str) {
+        mode = Mode::Status;
+        args.next();
+    } else if let Some("restore") = args.peek().map(String::as_str) {
+        mode = Mode::Restore;
+        args.next();
+    } else if let Some("watch") = args.peek().map(String::as_str) {
+        mode = Mode::Watch;
+        args.next();
+    } else if let Some("generate") = args.peek().map(String::as_str) {
+        mode = Mode::Generate;
+        args.next();
+    } else if let Some("dump") = args.peek().map(String::as_str) {
+        mode = Mode::Dump;
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        if let "--show" = arg.as_str() {
+            mode = Mode::Status;
+        } else if let "--format" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            format = parse_format(&value)?;
+        } else if let Some(value) = arg.strip_prefix("--format=") {
+            format = parse_format(value)?;
+        } else if let "--backend" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            backend = parse_backend(&value)?;
+        } else if let Some(value) = arg.strip_prefix("--backend=") {
+            backend = parse_backend(value)?;
+        } else if let "--set-policy" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            set_policy = Some(parse_policy(&value)?);
+        } else if let Some(value) = arg.strip_prefix("--set-policy=") {
+            set_policy = Some(parse_policy(value)?);
+        } else if let "--binary" | "--output-dir" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
 
-        if let "--enable-l0s" = arg.as_str() {
+            if arg == "--binary" {
+                binary = Some(value);
+            } else {
+                output_dir = Some(value);
+            }
+        } else if let "--lspci-dump" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            lspci_dump = Some(value);
+        } else if let Some(value) = arg.strip_prefix("--lspci-dump=") {
+            lspci_dump = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--binary=") {
+            binary = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--output-dir=") {
+            output_dir = Some(value.to_string());
+        } else if let "--interval" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            interval = Some(parse_interval(&value)?);
+        } else if let Some(value) = arg.strip_prefix("--interval=") {
+            interval = Some(parse_interval(value)?);
+        } else if let "--state-file" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            state_file = value;
+        } else if let Some(value) = arg.strip_prefix("--state-file=") {
+            state_file = value.to_string();
+        } else if let "--ltr-max-snoop-latency" | "--ltr-max-no-snoop-latency" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            let latency = Some(parse_ltr_latency(&arg, &value)?);
+
+            if arg == "--ltr-max-snoop-latency" {
+                ltr.max_snoop_latency = latency;
+            } else {
+                ltr.max_no_snoop_latency = latency;
+            }
+        } else if let Some(value) = arg.strip_prefix("--ltr-max-snoop-latency=") {
+            ltr.max_snoop_latency = Some(parse_ltr_latency(&arg, value)?);
+        } else if let Some(value) = arg.strip_prefix("--ltr-max-no-snoop-latency=") {
+            ltr.max_no_snoop_latency = Some(parse_ltr_latency(&arg, value)?);
+        } else if let "--enable-ltr" = arg.as_str() {
+            ltr.enable = Some(true);
+        } else if let "--disable-ltr" = arg.as_str() {
+            ltr.enable = Some(false);
+        } else if let "--program-l1.2-timing" = arg.as_str() {
+            program_l12_timing = true;
+        } else if let "--common-clock" = arg.as_str() {
+            common_clock = true;
+        } else if let "--dry-run" = arg.as_str() {
+            dry_run = true;
+        } else if let "--force" = arg.as_str() {
+            force = true;
+        } else if let "--ignore-firmware" = arg.as_str() {
+            ignore_firmware = true;
+        } else if let "--acpi-tables" = arg.as_str() {
+            let Some(value) = args.next() else {
+                return Err(Error::new("syntax", &arg, "missing value"));
+            };
+            acpi_tables = Some(value);
+        } else if let Some(value) = arg.strip_prefix("--acpi-tables=") {
+            acpi_tables = Some(value.to_string());
+        } else if let "--verbose" = arg.as_str() {
+            verbose = true;
+        } else if let "--all" = arg.as_str() {
+            all = true;
+        } else if let "--enable-l0s" = arg.as_str() {
             flags |= PCI_EXP_LNKCTL_ASPM_L0S;
             mask |= PCI_EXP_LNKCTL_ASPM_L0S;
         } else if let "--disable-l0s" = arg.as_str() {
@@ -86,78 +435,2078 @@ fn parse_args() -> Option<Args> {
         } else if let "--disable-l1" = arg.as_str() {
             flags &= !PCI_EXP_LNKCTL_ASPM_L1;
             mask |= PCI_EXP_LNKCTL_ASPM_L1;
+        } else if let "--enable-clkpm" = arg.as_str() {
+            flags |= PCI_EXP_LNKCTL_CLKREQ_EN;
+            mask |= PCI_EXP_LNKCTL_CLKREQ_EN;
+        } else if let "--disable-clkpm" = arg.as_str() {
+            flags &= !PCI_EXP_LNKCTL_CLKREQ_EN;
+            mask |= PCI_EXP_LNKCTL_CLKREQ_EN;
+        } else if let Some(bit) = l1ss_option_bit(&arg, "--enable-") {
+            l1ss_flags |= bit;
+            l1ss_mask |= bit;
+        } else if let Some(bit) = l1ss_option_bit(&arg, "--disable-") {
+            l1ss_flags &= !bit;
+            l1ss_mask |= bit;
         } else if arg.starts_with("--") {
-            eprintln!("syntax: {}: unrecognized option", arg);
-            return None;
-        } else if let None = path {
-            path = Some(arg);
+            return Err(Error::new("syntax", &arg, "unrecognized option"));
+        } else if mode == Mode::Generate {
+            let Some(address) = parse_pci_address(&arg) else {
+                return Err(Error::new("syntax", &arg, "invalid pci address"));
+            };
+            selectors.push(address);
+        } else if device.is_none() {
+            device = Some(arg);
         } else {
-            eprintln!("syntax: {}: path already specified", arg);
-            return None;
+            return Err(Error::new("syntax", &arg, "device already specified"));
         }
     }
 
-    let Some(path) = path else {
-        eprintln!("syntax: missing path");
-        return None;
+    if device.is_none()
+        && !all
+        // Without a device, status and dump cover every function in an lspci dump.
+        && lspci_dump.is_none()
+        && matches!(mode, Mode::Status | Mode::Watch | Mode::Dump)
+    {
+        return Err(Error::without_subject("syntax", "missing device"));
+    }
+
+    // Switching the kernel policy is the one change that needs no device.
+    if device.is_none() && !all && mode == Mode::Apply && set_policy.is_none() {
+        return Err(Error::without_subject("syntax", "missing device"));
+    }
+
+    if mode == Mode::Generate && selectors.is_empty() && !all && set_policy.is_none() {
+        return Err(Error::without_subject("syntax", "missing device"));
+    }
+
+    if mode == Mode::Generate && dry_run {
+        return Err(Error::without_subject(
+            "syntax",
+            "generate mode does not accept --dry-run",
+        ));
+    }
+
+    if mode == Mode::Generate
+        && (!selectors.is_empty() || all)
+        && mask == 0
+        && l1ss_mask == 0
+        && ltr == LtrRequest::default()
+    {
+        return Err(Error::without_subject(
+            "syntax",
+            "generate mode needs aspm options to apply",
+        ));
+    }
+
+    if mode != Mode::Generate && (binary.is_some() || output_dir.is_some()) {
+        return Err(Error::without_subject(
+            "syntax",
+            "--binary and --output-dir are only accepted in generate mode",
+        ));
+    }
+
+    // The generated files should not change with the order devices were listed in.
+    selectors.sort();
+    selectors.dedup();
+
+    if (device.is_some() || !selectors.is_empty()) && all {
+        return Err(Error::without_subject(
+            "syntax",
+            "--all does not accept a device",
+        ));
+    }
+
+    if mode == Mode::Status
+        && (mask != 0
+            || l1ss_mask != 0
+            || ltr != LtrRequest::default()
+            || set_policy.is_some()
+            || program_l12_timing
+            || common_clock
+            || dry_run)
+    {
+        return Err(Error::without_subject(
+            "syntax",
+            "status mode does not accept aspm options",
+        ));
+    }
+
+    if mode == Mode::Dump
+        && (mask != 0
+            || l1ss_mask != 0
+            || ltr != LtrRequest::default()
+            || set_policy.is_some()
+            || program_l12_timing
+            || common_clock
+            || dry_run)
+    {
+        return Err(Error::without_subject(
+            "syntax",
+            "dump mode does not accept aspm options",
+        ));
+    }
+
+    if mode == Mode::Restore
+        && (mask != 0
+            || l1ss_mask != 0
+            || ltr != LtrRequest::default()
+            || set_policy.is_some()
+            || program_l12_timing
+            || common_clock
+            || all)
+    {
+        return Err(Error::without_subject(
+            "syntax",
+            "restore mode does not accept aspm options",
+        ));
+    }
+
+    // Watch re-applies ASPM states; retraining or rewriting LTR on every drift would not do.
+    if mode == Mode::Watch && (ltr != LtrRequest::default() || program_l12_timing || common_clock) {
+        return Err(Error::without_subject(
+            "syntax",
+            "watch mode does not accept ltr options, --program-l1.2-timing or --common-clock",
+        ));
+    }
+
+    if mode == Mode::Watch && mask == 0 && l1ss_mask == 0 {
+        return Err(Error::without_subject(
+            "syntax",
+            "watch mode needs aspm options to watch",
+        ));
+    }
+
+    if mode != Mode::Watch && interval.is_some() {
+        return Err(Error::without_subject(
+            "syntax",
+            "--interval is only accepted in watch mode",
+        ));
+    }
+
+    if lspci_dump.is_some()
+        && (matches!(mode, Mode::Restore | Mode::Watch | Mode::Generate)
+            || set_policy.is_some()
+            || backend == Backend::SysfsLink)
+    {
+        return Err(Error::without_subject(
+            "syntax",
+            "--lspci-dump only accepts status and aspm options",
+        ));
+    }
+
+    // Nothing reaches the machine the dump came from, so only show what would change.
+    if lspci_dump.is_some() {
+        dry_run = true;
+    }
+
+    let source = match lspci_dump {
+        Some(path) => ConfigSource::LspciDump(read_lspci_dump(&path)?),
+        None => ConfigSource::Sysfs,
     };
 
-    return Some(Args {
-        path: path,
-        flags: flags,
-        mask: mask,
-    });
+    // The first two rewrite registers the kernel link controls know nothing about, and the
+    // kernel masks the states --force enables against the link capabilities.
+    if backend == Backend::SysfsLink && (program_l12_timing || common_clock || force) {
+        return Err(Error::without_subject(
+            "syntax",
+            "--backend=sysfs-link does not accept --program-l1.2-timing, --common-clock or --force",
+        ));
+    }
+
+    Ok(Args {
+        mode,
+        format,
+        device,
+        options: ApplyOptions {
+            request: AspmRequest {
+                mask,
+                flags,
+                l1ss_mask,
+                l1ss_flags,
+            },
+            ltr,
+            program_l12_timing,
+            common_clock,
+            dry_run,
+            force,
+            ignore_firmware,
+            backend,
+            state_file,
+            acpi_tables: acpi_tables.unwrap_or_else(|| SYSFS_ACPI_TABLES.to_string()),
+        },
+        set_policy,
+        verbose,
+        interval: interval.unwrap_or(DEFAULT_WATCH_INTERVAL),
+        binary: binary.unwrap_or_else(|| DEFAULT_GENERATE_BINARY.to_string()),
+        output_dir,
+        all,
+        selectors,
+        source,
+    })
 }
 
-fn main() -> ExitCode {
-    let Some(args) = parse_args() else {
-        return ExitCode::from(1);
+#[derive(Debug, Default)]
+struct Output {
+    format: Format,
+    devices: Vec<Json>,
+    results: Vec<Json>,
+    warnings: Vec<String>,
+    policy: Option<Json>,
+    /// What generate wrote or would write, by file name.
+    files: Option<Vec<Json>>,
+}
+
+fn report_warning(output: &mut Output, message: String) {
+    match output.format {
+        Format::Text => eprintln!("warning: {}", message),
+        Format::Json => output.warnings.push(message),
+    }
+}
+
+impl ApplyReporter for Output {
+    fn warning(&mut self, message: String) {
+        report_warning(self, message);
+    }
+
+    fn ltr_plans(&mut self, hierarchy: &[PciConfig], plans: &[LtrPlan], dry_run: bool) {
+        match self.format {
+            Format::Text if dry_run => {
+                for (config, plan) in hierarchy.iter().zip(plans) {
+                    print_ltr_plan(config, plan);
+                }
+            }
+            Format::Text => {}
+            Format::Json => {
+                for (config, plan) in hierarchy.iter().zip(plans) {
+                    self.devices.push(ltr_plan_json(config, plan));
+                }
+            }
+        }
+    }
+
+    fn aspm_plans(&mut self, link: &[PciConfig], plans: &[AspmPlan], execution: &AspmExecution) {
+        match self.format {
+            Format::Text if execution.dry_run => {
+                for (config, plan) in link.iter().zip(plans) {
+                    print_plan(config, plan);
+                }
+
+                for (attribute_path, enable) in execution.sysfs_writes.iter().flatten() {
+                    println!(
+                        "{}: {} -> {}",
+                        attribute_path,
+                        u8::from(!enable),
+                        u8::from(*enable)
+                    );
+                }
+            }
+            Format::Text => {}
+            Format::Json => {
+                for (config, plan) in link.iter().zip(plans) {
+                    self.devices.push(plan_json(
+                        config,
+                        plan,
+                        execution.backend.name(),
+                        config.writes != 0 || execution.sysfs_written,
+                        execution
+                            .completed
+                            .then(|| read_config_u16(&config.buffer, plan.link_control_offset)),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn finish_output(output: Output, result: Result<(), Error>) -> ExitCode {
+    match output.format {
+        Format::Text => {
+            if let Err(err) = &result {
+                eprintln!("{}", err);
+            }
+        }
+        Format::Json => {
+            let document = Json::Object(vec![
+                ("devices", Json::Array(output.devices)),
+                ("results", Json::Array(output.results)),
+                ("warnings", output.warnings.into()),
+                ("policy", output.policy.into()),
+                ("files", output.files.into()),
+                ("error", result.as_ref().err().map(Json::from).into()),
+            ]);
+
+            println!("{}", document);
+        }
+    }
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if err.context == "verify" => ExitCode::from(EXIT_VERIFY_FAILED),
+        Err(_) => ExitCode::from(1),
+    }
+}
+
+fn format_ltr_latency(value: u16) -> String {
+    match ltr_latency_ns(value) {
+        Some(latency_ns) => format!("0x{:04x} ({}ns)", value, latency_ns),
+        None => format!("0x{:04x} (reserved scale)", value),
+    }
+}
+
+fn print_status(config: &PciConfig, verbose: bool) -> Result<(), Error> {
+    let capability_range = find_pci_exp_capability(&config.buffer)?;
+    let link_control_range = find_pci_exp_link_control(&config.buffer)?;
+    let link_capabilities_range = find_pci_exp_link_capabilities(&config.buffer)?;
+    let link_control_value = read_config_u16(&config.buffer, link_control_range.start);
+    let link_capabilities_value = read_config_u32(&config.buffer, link_capabilities_range.start);
+
+    println!(
+        "{}: link control 0x{:04x} aspm {} (supported: {})",
+        config.path,
+        link_control_value,
+        aspm_control_name(link_control_value),
+        aspm_control_name(link_capabilities_aspm_support(link_capabilities_value))
+    );
+
+    println!(
+        "{}: clock power management {} ({})",
+        config.path,
+        if link_control_value & PCI_EXP_LNKCTL_CLKREQ_EN != 0 {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        if link_capabilities_value & PCI_EXP_LNKCAP_CLKPM != 0 {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+
+    let link_status_value = read_config_u16(
+        &config.buffer,
+        find_pci_exp_link_status(&config.buffer)?.start,
+    );
+
+    println!(
+        "{}: common clock configuration {} (slot clock {})",
+        config.path,
+        if link_control_value & PCI_EXP_LNKCTL_CCC != 0 {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        if link_status_value & PCI_EXP_LNKSTA_SLC != 0 {
+            "common"
+        } else {
+            "independent"
+        }
+    );
+
+    if let Some(l1ss_range) = find_pci_l1ss(&config.buffer)? {
+        let l1ss_capabilities_value =
+            read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CAP);
+        let l1ss_control_value = read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL1);
+
+        println!(
+            "{}: l1 pm substates control 0x{:08x} {} (supported: {})",
+            config.path,
+            l1ss_control_value,
+            l1ss_control_name(l1ss_control_value),
+            l1ss_control_name(l1ss_capabilities_value)
+        );
+    }
+
+    let device_control2_value =
+        read_config_u16(&config.buffer, capability_range.start + PCI_EXP_DEVCTL2);
+    let ltr_supported = ltr_supported(&config.buffer)?;
+
+    println!(
+        "{}: latency tolerance reporting {} ({})",
+        config.path,
+        if ltr_supported && device_control2_value & PCI_EXP_DEVCTL2_LTR_EN != 0 {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        if ltr_supported {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+
+    if let Some(ltr_range) = find_pci_ltr(&config.buffer)? {
+        println!(
+            "{}: ltr max snoop latency {} max no-snoop latency {}",
+            config.path,
+            format_ltr_latency(read_config_u16(
+                &config.buffer,
+                ltr_range.start + PCI_LTR_MAX_SNOOP_LAT
+            )),
+            format_ltr_latency(read_config_u16(
+                &config.buffer,
+                ltr_range.start + PCI_LTR_MAX_NOSNOOP_LAT
+            ))
+        );
+    }
+
+    let ext_capabilities = pci_ext_capabilities(&config.buffer)?;
+
+    if !ext_capabilities.is_empty() {
+        let ext_capabilities_description: Vec<String> = ext_capabilities
+            .iter()
+            .map(|capability| {
+                format!(
+                    "0x{:04x} v{} at 0x{:03x}",
+                    capability.id, capability.version, capability.offset
+                )
+            })
+            .collect();
+
+        println!(
+            "{}: extended capabilities {}",
+            config.path,
+            ext_capabilities_description.join(", ")
+        );
+    }
+
+    if verbose {
+        print_pcie_capability(config)?;
+    }
+
+    Ok(())
+}
+
+fn pcie_capability_registers(
+    capability: &PcieCapability,
+) -> Vec<(&'static str, usize, u32, String)> {
+    let mut registers = vec![
+        (
+            "DevCap",
+            8,
+            capability.device_capabilities.value,
+            capability.device_capabilities.to_string(),
+        ),
+        (
+            "DevCtl",
+            4,
+            capability.device_control.value as u32,
+            capability.device_control.to_string(),
+        ),
+        (
+            "DevSta",
+            4,
+            capability.device_status.value as u32,
+            capability.device_status.to_string(),
+        ),
+        (
+            "LnkCap",
+            8,
+            capability.link_capabilities.value,
+            capability.link_capabilities.to_string(),
+        ),
+        (
+            "LnkCtl",
+            4,
+            capability.link_control.value as u32,
+            capability.link_control.to_string(),
+        ),
+        (
+            "LnkSta",
+            4,
+            capability.link_status.value as u32,
+            capability.link_status.to_string(),
+        ),
+    ];
+
+    if let Some(slot_capabilities) = &capability.slot_capabilities {
+        registers.push((
+            "SltCap",
+            8,
+            slot_capabilities.value,
+            slot_capabilities.to_string(),
+        ));
+    }
+
+    if let Some(device_capabilities2) = &capability.device_capabilities2 {
+        registers.push((
+            "DevCap2",
+            8,
+            device_capabilities2.value,
+            device_capabilities2.to_string(),
+        ));
+    }
+
+    if let Some(link_capabilities2) = &capability.link_capabilities2 {
+        registers.push((
+            "LnkCap2",
+            8,
+            link_capabilities2.value,
+            link_capabilities2.to_string(),
+        ));
+    }
+
+    if let Some(link_control2) = &capability.link_control2 {
+        registers.push((
+            "LnkCtl2",
+            4,
+            link_control2.value as u32,
+            link_control2.to_string(),
+        ));
+    }
+
+    registers
+}
+
+fn print_pcie_capability(config: &PciConfig) -> Result<(), Error> {
+    let capability = decode_pcie_capability(&config.buffer)?;
+
+    println!(
+        "{}: capability 0x{:02x}: {}",
+        config.path, capability.offset, capability
+    );
+
+    for (name, width, value, description) in pcie_capability_registers(&capability) {
+        println!(
+            "{}: {} 0x{:0width$x}: {}",
+            config.path,
+            name,
+            value,
+            description,
+            width = width
+        );
+    }
+
+    Ok(())
+}
+
+fn pcie_capability_json(config: &PciConfig) -> Result<Json, Error> {
+    let capability = decode_pcie_capability(&config.buffer)?;
+
+    let registers: Vec<Json> = pcie_capability_registers(&capability)
+        .into_iter()
+        .map(|(name, _, value, description)| {
+            Json::Object(vec![
+                ("name", name.into()),
+                ("value", value.into()),
+                ("description", description.into()),
+            ])
+        })
+        .collect();
+
+    Ok(Json::Object(vec![
+        ("offset", capability.offset.into()),
+        ("version", capability.version.into()),
+        ("port_type", capability.port_type.to_string().into()),
+        ("slot_implemented", capability.slot_implemented.into()),
+        (
+            "interrupt_message_number",
+            capability.interrupt_message_number.into(),
+        ),
+        ("registers", Json::Array(registers)),
+    ]))
+}
+
+fn status_json(config: &PciConfig, verbose: bool) -> Result<Json, Error> {
+    let capability_range = find_pci_exp_capability(&config.buffer)?;
+    let link_control_value =
+        read_config_u16(&config.buffer, capability_range.start + PCI_EXP_LNKCTL);
+    let link_capabilities_value =
+        read_config_u32(&config.buffer, capability_range.start + PCI_EXP_LNKCAP);
+    let link_status_value =
+        read_config_u16(&config.buffer, capability_range.start + PCI_EXP_LNKSTA);
+    let l1ss_range = find_pci_l1ss(&config.buffer)?;
+
+    let (l1ss_capabilities, l1ss_control) = match &l1ss_range {
+        Some(l1ss_range) => {
+            let l1ss_capabilities_value =
+                read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CAP);
+            let l1ss_control_value =
+                read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL1);
+
+            (
+                Json::Object(vec![
+                    ("value", l1ss_capabilities_value.into()),
+                    (
+                        "supported",
+                        l1ss_control_names(l1ss_capabilities_value).into(),
+                    ),
+                ]),
+                Json::Object(vec![
+                    ("value", l1ss_control_value.into()),
+                    ("enabled", l1ss_control_names(l1ss_control_value).into()),
+                ]),
+            )
+        }
+        None => (Json::Null, Json::Null),
     };
 
-    let mut config_file = match std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&args.path)
-    {
-        Ok(value) => value,
-        Err(err) => {
-            eprintln!("open: {}: {}", args.path, err);
-            return ExitCode::from(1);
+    let ltr_supported = ltr_supported(&config.buffer)?;
+    let device_control2_value =
+        read_config_u16(&config.buffer, capability_range.start + PCI_EXP_DEVCTL2);
+
+    let ltr_max_latency = match find_pci_ltr(&config.buffer)? {
+        Some(ltr_range) => {
+            let max_snoop_latency_value =
+                read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_SNOOP_LAT);
+            let max_no_snoop_latency_value =
+                read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_NOSNOOP_LAT);
+
+            Json::Object(vec![
+                ("max_snoop_latency", max_snoop_latency_value.into()),
+                (
+                    "max_snoop_latency_ns",
+                    ltr_latency_ns(max_snoop_latency_value).into(),
+                ),
+                ("max_no_snoop_latency", max_no_snoop_latency_value.into()),
+                (
+                    "max_no_snoop_latency_ns",
+                    ltr_latency_ns(max_no_snoop_latency_value).into(),
+                ),
+            ])
         }
+        None => Json::Null,
     };
 
-    let mut config_buffer = Vec::<u8>::new();
+    let ext_capabilities: Vec<Json> = pci_ext_capabilities(&config.buffer)?
+        .iter()
+        .map(|capability| {
+            Json::Object(vec![
+                ("id", capability.id.into()),
+                ("version", (capability.version as u16).into()),
+                ("offset", capability.offset.into()),
+            ])
+        })
+        .collect();
 
-    if let Err(err) = config_file
-        .by_ref()
-        .take(256)
-        .read_to_end(&mut config_buffer)
-    {
-        eprintln!("read: {}: {}", args.path, err);
-        return ExitCode::from(1);
+    Ok(Json::Object(vec![
+        ("device", pci_device_name(&config.path).into()),
+        ("path", config.path.as_str().into()),
+        (
+            "capabilities",
+            Json::Object(vec![
+                ("pci_express", capability_range.start.into()),
+                ("l1ss", l1ss_range.map(|l1ss_range| l1ss_range.start).into()),
+            ]),
+        ),
+        (
+            "link_capabilities",
+            Json::Object(vec![
+                ("value", link_capabilities_value.into()),
+                (
+                    "aspm_support",
+                    aspm_control_names(link_capabilities_aspm_support(link_capabilities_value))
+                        .into(),
+                ),
+                (
+                    "clock_pm",
+                    (link_capabilities_value & PCI_EXP_LNKCAP_CLKPM != 0).into(),
+                ),
+            ]),
+        ),
+        (
+            "link_control",
+            Json::Object(vec![
+                ("value", link_control_value.into()),
+                ("aspm", aspm_control_names(link_control_value).into()),
+                (
+                    "clock_pm",
+                    (link_control_value & PCI_EXP_LNKCTL_CLKREQ_EN != 0).into(),
+                ),
+                (
+                    "common_clock",
+                    (link_control_value & PCI_EXP_LNKCTL_CCC != 0).into(),
+                ),
+            ]),
+        ),
+        (
+            "link_status",
+            Json::Object(vec![
+                ("value", link_status_value.into()),
+                (
+                    "slot_clock",
+                    (link_status_value & PCI_EXP_LNKSTA_SLC != 0).into(),
+                ),
+            ]),
+        ),
+        ("l1ss_capabilities", l1ss_capabilities),
+        ("l1ss_control", l1ss_control),
+        (
+            "ltr",
+            Json::Object(vec![
+                ("supported", ltr_supported.into()),
+                (
+                    "enabled",
+                    (ltr_supported && device_control2_value & PCI_EXP_DEVCTL2_LTR_EN != 0).into(),
+                ),
+                ("max_latency", ltr_max_latency),
+            ]),
+        ),
+        ("extended_capabilities", Json::Array(ext_capabilities)),
+        (
+            "pci_express_capability",
+            if verbose {
+                pcie_capability_json(config)?
+            } else {
+                Json::Null
+            },
+        ),
+    ]))
+}
+
+fn print_plan(config: &PciConfig, plan: &AspmPlan) {
+    if let Some(timing) = &plan.l12_timing {
+        println!(
+            "{}: l1.2 timing l1 pm substates control 1 0x{:08x} control 2 0x{:08x}",
+            config.path, timing.l1ss_control1_timing_value, timing.l1ss_control2_value
+        );
+    }
+
+    if plan.link_control_new_value == plan.link_control_old_value {
+        println!(
+            "{}: link control 0x{:04x} unchanged",
+            config.path, plan.link_control_old_value
+        );
+    } else {
+        println!(
+            "{}: link control 0x{:04x} -> 0x{:04x} ({})",
+            config.path,
+            plan.link_control_old_value,
+            plan.link_control_new_value,
+            bit_difference_names(
+                plan.link_control_old_value as u32,
+                plan.link_control_new_value as u32,
+                &PCI_EXP_LNKCTL_NAMES
+            )
+        );
+    }
+
+    if plan.l1ss_control_offset.is_none() {
+        return;
     }
 
-    let Some(link_control_range) = find_pci_exp_link_control(&config_buffer) else {
-        return ExitCode::from(1);
+    if plan.l1ss_control_new_value == plan.l1ss_control_old_value {
+        println!(
+            "{}: l1 pm substates control 0x{:08x} unchanged",
+            config.path, plan.l1ss_control_old_value
+        );
+    } else {
+        println!(
+            "{}: l1 pm substates control 0x{:08x} -> 0x{:08x} ({})",
+            config.path,
+            plan.l1ss_control_old_value,
+            plan.l1ss_control_new_value,
+            bit_difference_names(
+                plan.l1ss_control_old_value,
+                plan.l1ss_control_new_value,
+                &PCI_L1SS_CTL1_NAMES
+            )
+        );
+    }
+
+    if plan.l1ss_control2_new_value != plan.l1ss_control2_old_value {
+        println!(
+            "{}: l1 pm substates control 2 0x{:08x} -> 0x{:08x}",
+            config.path, plan.l1ss_control2_old_value, plan.l1ss_control2_new_value
+        );
+    }
+}
+
+fn plan_json(
+    config: &PciConfig,
+    plan: &AspmPlan,
+    backend: &str,
+    written: bool,
+    read_back_value: Option<u16>,
+) -> Json {
+    let l1ss_control = match plan.l1ss_control_offset {
+        Some(_) => Json::Object(vec![
+            ("old_value", plan.l1ss_control_old_value.into()),
+            ("new_value", plan.l1ss_control_new_value.into()),
+            (
+                "old",
+                l1ss_control_names(plan.l1ss_control_old_value).into(),
+            ),
+            (
+                "new",
+                l1ss_control_names(plan.l1ss_control_new_value).into(),
+            ),
+            ("control2_old_value", plan.l1ss_control2_old_value.into()),
+            ("control2_new_value", plan.l1ss_control2_new_value.into()),
+        ]),
+        None => Json::Null,
     };
 
-    let link_control_old_value = ((config_buffer[link_control_range.start + 1] as u16) << 8)
-        | (config_buffer[link_control_range.start] as u16);
-    let link_control_new_value = (link_control_old_value & !args.mask) | args.flags;
+    let l12_timing = match &plan.l12_timing {
+        Some(timing) => Json::Object(vec![
+            ("l1ss_control1", timing.l1ss_control1_timing_value.into()),
+            ("l1ss_control2", timing.l1ss_control2_value.into()),
+        ]),
+        None => Json::Null,
+    };
 
-    if link_control_new_value != link_control_old_value {
-        let link_control_new_buffer: [u8; 2] = [
-            link_control_new_value as u8,
-            (link_control_new_value >> 8) as u8,
-        ];
+    Json::Object(vec![
+        ("device", pci_device_name(&config.path).into()),
+        ("path", config.path.as_str().into()),
+        (
+            "capabilities",
+            Json::Object(vec![
+                (
+                    "pci_express",
+                    (plan.link_control_offset - PCI_EXP_LNKCTL).into(),
+                ),
+                (
+                    "l1ss",
+                    find_pci_l1ss(&config.buffer)
+                        .ok()
+                        .flatten()
+                        .map(|l1ss_range| l1ss_range.start)
+                        .into(),
+                ),
+            ]),
+        ),
+        (
+            "link_capabilities",
+            Json::Object(vec![
+                ("value", plan.link_capabilities_value.into()),
+                (
+                    "aspm_support",
+                    aspm_control_names(link_capabilities_aspm_support(
+                        plan.link_capabilities_value,
+                    ))
+                    .into(),
+                ),
+                (
+                    "clock_pm",
+                    (plan.link_capabilities_value & PCI_EXP_LNKCAP_CLKPM != 0).into(),
+                ),
+            ]),
+        ),
+        (
+            "link_control",
+            Json::Object(vec![
+                ("old_value", plan.link_control_old_value.into()),
+                ("new_value", plan.link_control_new_value.into()),
+                ("read_back_value", read_back_value.into()),
+                (
+                    "old_aspm",
+                    aspm_control_names(plan.link_control_old_value).into(),
+                ),
+                (
+                    "new_aspm",
+                    aspm_control_names(plan.link_control_new_value).into(),
+                ),
+                (
+                    "old_clock_pm",
+                    (plan.link_control_old_value & PCI_EXP_LNKCTL_CLKREQ_EN != 0).into(),
+                ),
+                (
+                    "new_clock_pm",
+                    (plan.link_control_new_value & PCI_EXP_LNKCTL_CLKREQ_EN != 0).into(),
+                ),
+            ]),
+        ),
+        ("l1ss_control", l1ss_control),
+        ("l12_timing", l12_timing),
+        ("backend", backend.into()),
+        ("written", written.into()),
+    ])
+}
 
-        if let Err(err) =
-            config_file.write_all_at(&link_control_new_buffer, link_control_range.start as u64)
-        {
-            eprintln!("write: {}: {}", args.path, err);
-            return ExitCode::from(1);
+fn print_ltr_plan(config: &PciConfig, plan: &LtrPlan) {
+    if plan.device_control2_new_value == plan.device_control2_old_value {
+        println!(
+            "{}: device control 2 0x{:04x} unchanged",
+            config.path, plan.device_control2_old_value
+        );
+    } else {
+        println!(
+            "{}: device control 2 0x{:04x} -> 0x{:04x} ({}LTR)",
+            config.path,
+            plan.device_control2_old_value,
+            plan.device_control2_new_value,
+            if plan.device_control2_new_value & PCI_EXP_DEVCTL2_LTR_EN != 0 {
+                "+"
+            } else {
+                "-"
+            }
+        );
+    }
+
+    if plan.max_snoop_latency_new_value != plan.max_snoop_latency_old_value {
+        println!(
+            "{}: ltr max snoop latency {} -> {}",
+            config.path,
+            format_ltr_latency(plan.max_snoop_latency_old_value),
+            format_ltr_latency(plan.max_snoop_latency_new_value)
+        );
+    }
+
+    if plan.max_no_snoop_latency_new_value != plan.max_no_snoop_latency_old_value {
+        println!(
+            "{}: ltr max no-snoop latency {} -> {}",
+            config.path,
+            format_ltr_latency(plan.max_no_snoop_latency_old_value),
+            format_ltr_latency(plan.max_no_snoop_latency_new_value)
+        );
+    }
+}
+
+fn ltr_plan_json(config: &PciConfig, plan: &LtrPlan) -> Json {
+    let ltr_max_latency = match plan.ltr_offset {
+        Some(_) => Json::Object(vec![
+            (
+                "max_snoop_latency_old_value",
+                plan.max_snoop_latency_old_value.into(),
+            ),
+            (
+                "max_snoop_latency_new_value",
+                plan.max_snoop_latency_new_value.into(),
+            ),
+            (
+                "max_no_snoop_latency_old_value",
+                plan.max_no_snoop_latency_old_value.into(),
+            ),
+            (
+                "max_no_snoop_latency_new_value",
+                plan.max_no_snoop_latency_new_value.into(),
+            ),
+        ]),
+        None => Json::Null,
+    };
+
+    Json::Object(vec![
+        ("device", pci_device_name(&config.path).into()),
+        ("path", config.path.as_str().into()),
+        (
+            "device_control2",
+            Json::Object(vec![
+                ("old_value", plan.device_control2_old_value.into()),
+                ("new_value", plan.device_control2_new_value.into()),
+                (
+                    "old_ltr",
+                    (plan.device_control2_old_value & PCI_EXP_DEVCTL2_LTR_EN != 0).into(),
+                ),
+                (
+                    "new_ltr",
+                    (plan.device_control2_new_value & PCI_EXP_DEVCTL2_LTR_EN != 0).into(),
+                ),
+            ]),
+        ),
+        ("ltr_max_latency", ltr_max_latency),
+        ("written", (config.writes != 0).into()),
+    ])
+}
+
+fn run_device(args: &Args, device: &str, output: &mut Output) -> Result<(), Error> {
+    let config_path = args.source.resolve(device)?;
+
+    if args.mode == Mode::Status {
+        let config = args.source.open(&config_path, false)?;
+
+        match output.format {
+            Format::Text => {
+                print_status(&config, args.verbose).map_err(|err| err.with_subject(&config.path))?
+            }
+            Format::Json => output.devices.push(
+                status_json(&config, args.verbose).map_err(|err| err.with_subject(&config.path))?,
+            ),
+        }
+
+        return Ok(());
+    }
+
+    let mut link = args.source.open_link(&config_path, !args.options.dry_run)?;
+
+    if link.len() < 2 {
+        if args.options.program_l12_timing || args.options.common_clock {
+            return Err(Error::new(
+                "error",
+                &config_path,
+                "unable to find upstream port",
+            ));
         }
+
+        report_warning(
+            output,
+            format!(
+                "{}: unable to find upstream port, configuring this device only",
+                config_path
+            ),
+        );
     }
 
-    ExitCode::SUCCESS
+    apply_link(&args.options, &args.source, &mut link, output)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum LinkResult {
+    Updated,
+    WouldUpdate,
+    Unchanged,
+    Skipped(String),
+    Failed(Error),
+}
+
+fn report_results(
+    output: &mut Output,
+    results: std::collections::BTreeMap<String, LinkResult>,
+) -> Result<(), Error> {
+    let failed = results
+        .values()
+        .filter(|result| matches!(result, LinkResult::Failed(_)))
+        .count();
+    let verify_failed = results
+        .values()
+        .filter(|result| matches!(result, LinkResult::Failed(err) if err.context == "verify"))
+        .count();
+
+    for (name, result) in results {
+        match output.format {
+            Format::Text => match result {
+                LinkResult::Updated => println!("{}: updated", name),
+                LinkResult::WouldUpdate => println!("{}: would update", name),
+                LinkResult::Unchanged => println!("{}: unchanged", name),
+                LinkResult::Skipped(reason) => println!("{}: skipped ({})", name, reason),
+                LinkResult::Failed(err) => println!("{}: failed ({})", name, err),
+            },
+            Format::Json => {
+                let (result, detail) = match &result {
+                    LinkResult::Updated => ("updated", None),
+                    LinkResult::WouldUpdate => ("would update", None),
+                    LinkResult::Unchanged => ("unchanged", None),
+                    LinkResult::Skipped(reason) => {
+                        ("skipped", Some(("reason", reason.as_str().into())))
+                    }
+                    LinkResult::Failed(err) => ("failed", Some(("error", err.into()))),
+                };
+
+                let mut fields = vec![("device", name.into()), ("result", result.into())];
+                fields.extend(detail);
+
+                output.results.push(Json::Object(fields));
+            }
+        }
+    }
+
+    if failed != 0 {
+        // Only report a verification failure when nothing else went wrong.
+        let context = if verify_failed == failed {
+            "verify"
+        } else {
+            "error"
+        };
+
+        return Err(Error::without_subject(
+            context,
+            format!("{} device(s) failed", failed),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Groups every PCI Express function by the downstream port above it. Functions outside any
+/// link get a result saying why; in status mode each function is reported instead.
+fn find_pci_links(
+    args: &Args,
+    output: &mut Output,
+    results: &mut std::collections::BTreeMap<String, LinkResult>,
+) -> Result<std::collections::BTreeMap<String, Vec<String>>, Error> {
+    let config_paths = args.source.config_paths()?;
+
+    let mut links = std::collections::BTreeMap::<String, Vec<String>>::new();
+    let mut downstream_ports = Vec::new();
+
+    for config_path in &config_paths {
+        let name = args.source.device_name(config_path);
+
+        let config = match args.source.open(config_path, false) {
+            Ok(value) => value,
+            Err(err) => {
+                results.insert(name, LinkResult::Failed(err));
+                continue;
+            }
+        };
+
+        match find_pci_capability(&config.buffer, PCI_CAP_ID_EXP, PCI_CAP_ID_EXP_LEN) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                results.insert(
+                    name,
+                    LinkResult::Skipped("no pci express capability".to_string()),
+                );
+                continue;
+            }
+            Err(err) => {
+                results.insert(name, LinkResult::Failed(err.with_subject(config_path)));
+                continue;
+            }
+        }
+
+        if args.mode == Mode::Status {
+            let status = match output.format {
+                Format::Text => print_status(&config, args.verbose),
+                Format::Json => {
+                    status_json(&config, args.verbose).map(|status| output.devices.push(status))
+                }
+            };
+
+            if let Err(err) = status {
+                results.insert(name, LinkResult::Failed(err.with_subject(config_path)));
+            }
+            continue;
+        }
+
+        if is_pcie_downstream_port(&config.buffer) {
+            downstream_ports.push(config_path.clone());
+        }
+
+        let upstream_path = args
+            .source
+            .find_upstream(config_path)
+            .filter(|upstream_path| {
+                args.source
+                    .open(upstream_path, false)
+                    .is_ok_and(|upstream_config| is_pcie_downstream_port(&upstream_config.buffer))
+            });
+
+        if let Some(upstream_path) = upstream_path {
+            links
+                .entry(upstream_path)
+                .or_default()
+                .push(config_path.clone());
+        } else if !is_pcie_downstream_port(&config.buffer) {
+            results.insert(name, LinkResult::Skipped("no link".to_string()));
+        }
+    }
+
+    for config_path in &downstream_ports {
+        let has_link = args
+            .source
+            .canonical_path(config_path)
+            .is_some_and(|path| links.contains_key(&path));
+
+        if !has_link {
+            results.insert(
+                args.source.device_name(config_path),
+                LinkResult::Skipped("no link".to_string()),
+            );
+        }
+    }
+
+    Ok(links)
+}
+
+fn run_all(args: &Args, output: &mut Output) -> Result<(), Error> {
+    let mut results = std::collections::BTreeMap::<String, LinkResult>::new();
+    let links = find_pci_links(args, output, &mut results)?;
+
+    for (upstream_path, downstream_paths) in &links {
+        let link_paths: Vec<&String> = std::iter::once(upstream_path)
+            .chain(downstream_paths)
+            .collect();
+
+        let result = match link_paths
+            .iter()
+            .map(|config_path| args.source.open(config_path, !args.options.dry_run))
+            .collect::<Result<Vec<_>, Error>>()
+        {
+            Err(err) => LinkResult::Failed(err),
+            Ok(mut link) => match (
+                link_skip_reason(&args.options, &args.source, &link),
+                args.options.force,
+            ) {
+                (Some(reason), false) => LinkResult::Skipped(reason),
+                _ => match apply_link(&args.options, &args.source, &mut link, output) {
+                    Ok(true) if args.options.dry_run => LinkResult::WouldUpdate,
+                    Ok(true) => LinkResult::Updated,
+                    Ok(false) => LinkResult::Unchanged,
+                    Err(err) => LinkResult::Failed(err),
+                },
+            },
+        };
+
+        for config_path in link_paths {
+            results.insert(args.source.device_name(config_path), result.clone());
+        }
+    }
+
+    report_results(output, results)
+}
+
+/// Prints configuration space the way `lspci -n -xxxx` does, so `lspci -F` can read it back.
+fn run_dump(args: &Args, output: &mut Output) -> Result<(), Error> {
+    let config_paths = match &args.device {
+        Some(device) => vec![args.source.resolve(device)?],
+        None => args.source.config_paths()?,
+    };
+
+    for config_path in &config_paths {
+        let config = args.source.open(config_path, false)?;
+        let device_name = args.source.device_name(config_path);
+        let dump = format_lspci_dump(&device_name, &config.buffer);
+
+        match output.format {
+            Format::Text => print!("{}", dump),
+            Format::Json => output.devices.push(Json::Object(vec![
+                ("device", device_name.into()),
+                ("path", config_path.as_str().into()),
+                ("dump", dump.into()),
+            ])),
+        }
+    }
+
+    Ok(())
+}
+
+fn run_restore(args: &Args, output: &mut Output) -> Result<(), Error> {
+    let mut states = read_state_file(&args.options.state_file)?;
+
+    let mut targets: Vec<SavedState> = match &args.device {
+        Some(device) => {
+            let config_path = resolve_config_path(device)?;
+            let link_devices: Vec<String> = open_pci_link(&config_path, false)?
+                .iter()
+                .map(|config| pci_device_name(&config.path))
+                .collect();
+
+            let targets: Vec<SavedState> = states
+                .iter()
+                .filter(|state| link_devices.contains(&state.device))
+                .cloned()
+                .collect();
+
+            if targets.is_empty() {
+                return Err(Error::new("error", device, "no saved state"));
+            }
+
+            targets
+        }
+        None => states.clone(),
+    };
+
+    // Restore both ends of a link together so the writes keep the spec-mandated order.
+    let mut links = std::collections::BTreeMap::<String, Vec<SavedState>>::new();
+
+    targets.sort_by_key(|state| state.device.clone());
+
+    // Bus numbers grow away from the root, so in address order every port comes before the
+    // functions below it.
+    let mut ltr_functions = Vec::new();
+    let mut ltr_plans = Vec::new();
+    let mut ltr_failures = std::collections::BTreeMap::<String, Error>::new();
+
+    for state in &targets {
+        let config_path = std::path::Path::new(SYSFS_PCI_DEVICES)
+            .join(&state.device)
+            .join("config");
+
+        match open_pci_config(&config_path.to_string_lossy(), !args.options.dry_run)
+            .and_then(|config| Ok((plan_ltr_restore(&config, state)?, config)))
+        {
+            Ok((Some(plan), config)) => {
+                ltr_functions.push(config);
+                ltr_plans.push(plan);
+            }
+            Ok((None, _)) => {}
+            Err(err) => {
+                ltr_failures.insert(state.device.clone(), err);
+            }
+        }
+    }
+
+    // L1.2 entry depends on LTR, so LTR comes back before ASPM unless restoring turns it off.
+    let ltr_disabling = ltr_plans.iter().any(|plan| {
+        plan.device_control2_old_value & !plan.device_control2_new_value & PCI_EXP_DEVCTL2_LTR_EN
+            != 0
+    });
+
+    let mut ltr_changes = Vec::new();
+
+    if !ltr_disabling {
+        ltr_changes = restore_ltr(&args.options, &mut ltr_functions, &ltr_plans, output)?;
+    }
+
+    for state in targets {
+        let config_path = std::path::Path::new(SYSFS_PCI_DEVICES)
+            .join(&state.device)
+            .join("config");
+
+        let upstream_device = find_upstream_config_path(&config_path.to_string_lossy())
+            .filter(|upstream_path| {
+                open_pci_config(upstream_path, false)
+                    .is_ok_and(|upstream_config| is_pcie_downstream_port(&upstream_config.buffer))
+            })
+            .map(|upstream_path| pci_device_name(&upstream_path))
+            .filter(|upstream_device| links.contains_key(upstream_device));
+
+        links
+            .entry(upstream_device.unwrap_or_else(|| state.device.clone()))
+            .or_default()
+            .push(state);
+    }
+
+    let mut results = std::collections::BTreeMap::<String, LinkResult>::new();
+
+    for link_states in links.values() {
+        let result = match link_states
+            .iter()
+            .map(|state| {
+                let config_path = std::path::Path::new(SYSFS_PCI_DEVICES)
+                    .join(&state.device)
+                    .join("config");
+                let config =
+                    open_pci_config(&config_path.to_string_lossy(), !args.options.dry_run)?;
+                let plan = plan_restore(&config, state)?;
+
+                Ok((config, plan))
+            })
+            .collect::<Result<(Vec<_>, Vec<_>), Error>>()
+        {
+            Err(err) => LinkResult::Failed(err),
+            Ok((mut link, mut plans)) => {
+                match restore_link(&args.options, &args.source, &mut link, &mut plans, output) {
+                    Ok(true) if args.options.dry_run => LinkResult::WouldUpdate,
+                    Ok(true) => LinkResult::Updated,
+                    Ok(false) => LinkResult::Unchanged,
+                    Err(err) => LinkResult::Failed(err),
+                }
+            }
+        };
+
+        if !args.options.dry_run && !matches!(result, LinkResult::Failed(_)) {
+            states.retain(|state| {
+                ltr_failures.contains_key(&state.device)
+                    || !link_states
+                        .iter()
+                        .any(|link_state| link_state.device == state.device)
+            });
+        }
+
+        for state in link_states {
+            results.insert(state.device.clone(), result.clone());
+        }
+    }
+
+    if ltr_disabling {
+        ltr_changes = restore_ltr(&args.options, &mut ltr_functions, &ltr_plans, output)?;
+    }
+
+    for (config, changed) in ltr_functions.iter().zip(ltr_changes) {
+        if let (true, Some(result @ LinkResult::Unchanged)) =
+            (changed, results.get_mut(&pci_device_name(&config.path)))
+        {
+            *result = if args.options.dry_run {
+                LinkResult::WouldUpdate
+            } else {
+                LinkResult::Updated
+            };
+        }
+    }
+
+    for (device, err) in ltr_failures {
+        results.insert(device, LinkResult::Failed(err));
+    }
+
+    if !args.options.dry_run {
+        write_state_file(&args.options.state_file, &states)?;
+    }
+
+    report_results(output, results)
+}
+
+fn policy_json(
+    kernel_aspm_disabled: bool,
+    old_policy: Option<AspmPolicy>,
+    new_policy: Option<AspmPolicy>,
+    written: bool,
+    conflicts: Vec<String>,
+) -> Json {
+    Json::Object(vec![
+        ("path", SYSFS_PCIE_ASPM_POLICY.into()),
+        ("kernel_aspm_disabled", kernel_aspm_disabled.into()),
+        ("old", old_policy.map(|policy| policy.name()).into()),
+        ("new", new_policy.map(|policy| policy.name()).into()),
+        ("written", written.into()),
+        ("conflicts", conflicts.into()),
+    ])
+}
+
+/// Checks the kernel policy against the request and reports conflicts. Returns the policy
+/// `write_kernel_policy` writes once everything else went through, if any.
+fn check_kernel_policy(args: &Args, output: &mut Output) -> Result<Option<AspmPolicy>, Error> {
+    if args.source.is_offline() {
+        return Ok(None);
+    }
+
+    // Without a readable command line the policy file is the only source.
+    let cmdline = std::fs::read_to_string(PROC_CMDLINE).unwrap_or_default();
+
+    if kernel_aspm_disabled(&cmdline) {
+        if args.set_policy.is_some() {
+            return Err(Error::new(
+                "error",
+                SYSFS_PCIE_ASPM_POLICY,
+                "kernel aspm disabled by pcie_aspm=off, policy cannot be changed",
+            ));
+        }
+
+        report_warning(
+            output,
+            format!(
+                "{}: kernel aspm disabled by pcie_aspm=off, the kernel does not manage aspm",
+                PROC_CMDLINE
+            ),
+        );
+
+        output.policy = Some(policy_json(true, None, None, false, Vec::new()));
+
+        return Ok(None);
+    }
+
+    let current_policy = read_aspm_policy(SYSFS_PCIE_ASPM_POLICY)?;
+
+    let policy = match args.set_policy {
+        Some(policy) => {
+            if args.options.dry_run && output.format == Format::Text {
+                println!(
+                    "{}: policy {} -> {}",
+                    SYSFS_PCIE_ASPM_POLICY,
+                    current_policy.map_or("unavailable", |policy| policy.name()),
+                    policy
+                );
+            }
+
+            Some(policy)
+        }
+        None => current_policy,
+    };
+
+    let conflicts = policy
+        .map(|policy| aspm_policy_conflicts(policy, &args.options.request))
+        .unwrap_or_default();
+
+    output.policy = Some(policy_json(
+        false,
+        current_policy,
+        policy,
+        false,
+        conflicts.clone(),
+    ));
+
+    let Some(policy) = policy else {
+        return Ok(None);
+    };
+
+    for conflict in conflicts {
+        report_warning(
+            output,
+            format!(
+                "{}: {} conflicts with kernel policy {}, the kernel will revert it (use --set-policy to change the policy)",
+                SYSFS_PCIE_ASPM_POLICY, conflict, policy
+            ),
+        );
+    }
+
+    Ok(args.set_policy.filter(|_| !args.options.dry_run))
+}
+
+fn write_kernel_policy(policy: AspmPolicy, output: &mut Output) -> Result<(), Error> {
+    write_aspm_policy(SYSFS_PCIE_ASPM_POLICY, policy)?;
+
+    if let Some(Json::Object(fields)) = &mut output.policy {
+        for (name, value) in fields.iter_mut() {
+            if *name == "written" {
+                *value = true.into();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_apply(args: &Args, device: Option<&str>, output: &mut Output) -> Result<(), Error> {
+    let new_policy = check_kernel_policy(args, output)?;
+    check_firmware_aspm(&args.options, &args.source, output)?;
+
+    match device {
+        Some(device) => run_device(args, device, output)?,
+        None if args.all => run_all(args, output)?,
+        None => {}
+    }
+
+    // Only once every device went through, so a refused request leaves the policy alone.
+    match new_policy {
+        Some(policy) => write_kernel_policy(policy, output),
+        None => Ok(()),
+    }
+}
+
+fn watch_link_paths(args: &Args, output: &mut Output) -> Result<Vec<Vec<String>>, Error> {
+    match &args.device {
+        Some(device) => {
+            let config_path = resolve_config_path(device)?;
+            let link_paths = open_pci_link(&config_path, false)?
+                .into_iter()
+                .map(|config| config.path)
+                .collect();
+
+            Ok(vec![link_paths])
+        }
+        None => {
+            let mut results = std::collections::BTreeMap::new();
+
+            Ok(find_pci_links(args, output, &mut results)?
+                .into_iter()
+                .map(|(upstream_path, downstream_paths)| {
+                    std::iter::once(upstream_path)
+                        .chain(downstream_paths)
+                        .collect()
+                })
+                .collect())
+        }
+    }
+}
+
+/// Link Control and L1 PM Substates Control bits of one function that read back different
+/// from what was written, so watch stops restoring them.
+#[derive(Debug, Default, Clone, Copy)]
+struct StuckBits {
+    link_control: u16,
+    l1ss_control: u32,
+}
+
+impl StuckBits {
+    /// Link Control and L1 PM Substates Control bits `plan` changes, other than stuck ones.
+    fn changes(&self, plan: &AspmPlan) -> (u16, u32) {
+        (
+            (plan.link_control_new_value ^ plan.link_control_old_value) & !self.link_control,
+            (plan.l1ss_control_new_value ^ plan.l1ss_control_old_value) & !self.l1ss_control,
+        )
+    }
+}
+
+fn report_drift(
+    args: &Args,
+    config: &PciConfig,
+    register: &str,
+    drifted_value: u32,
+    expected_value: u32,
+    output: &mut Output,
+) {
+    let (description, width, names): (&str, usize, &[(u32, &str)]) = match register {
+        "link_control" => ("link control", 4, &PCI_EXP_LNKCTL_NAMES),
+        _ => ("l1 pm substates control", 8, &PCI_L1SS_CTL1_NAMES),
+    };
+
+    match output.format {
+        Format::Text => println!(
+            "{}: {} drifted to 0x{:0width$x}, {} 0x{:0width$x} ({})",
+            config.path,
+            description,
+            drifted_value,
+            if args.options.dry_run {
+                "would restore"
+            } else {
+                "restoring"
+            },
+            expected_value,
+            bit_difference_names(drifted_value, expected_value, names),
+            width = width,
+        ),
+        Format::Json => println!(
+            "{}",
+            Json::Object(vec![
+                ("device", pci_device_name(&config.path).into()),
+                ("path", config.path.as_str().into()),
+                ("register", register.into()),
+                ("drifted_value", drifted_value.into()),
+                ("expected_value", expected_value.into()),
+                (
+                    "changes",
+                    bit_difference_names(drifted_value, expected_value, names).into()
+                ),
+                ("dry_run", args.options.dry_run.into()),
+            ])
+        ),
+    }
+}
+
+fn watch_link(
+    args: &Args,
+    link_paths: &[String],
+    stuck_bits: &mut std::collections::BTreeMap<String, StuckBits>,
+    output: &mut Output,
+) -> Result<(), Error> {
+    let link = link_paths
+        .iter()
+        .map(|config_path| open_pci_config(config_path, false))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // Links the requested states cannot apply to were never configured, so they cannot drift.
+    if !args.options.force && link_skip_reason(&args.options, &args.source, &link).is_some() {
+        return Ok(());
+    }
+
+    let mut drifted = false;
+
+    for config in &link {
+        let plan = plan_aspm(&args.options.request, config)?;
+        let (link_control_bits, l1ss_control_bits) = stuck_bits
+            .get(&config.path)
+            .copied()
+            .unwrap_or_default()
+            .changes(&plan);
+
+        if link_control_bits != 0 {
+            drifted = true;
+
+            report_drift(
+                args,
+                config,
+                "link_control",
+                plan.link_control_old_value as u32,
+                plan.link_control_new_value as u32,
+                output,
+            );
+        }
+
+        if l1ss_control_bits != 0 {
+            drifted = true;
+
+            report_drift(
+                args,
+                config,
+                "l1ss_control",
+                plan.l1ss_control_old_value,
+                plan.l1ss_control_new_value,
+                output,
+            );
+        }
+    }
+
+    if !drifted || args.options.dry_run {
+        return Ok(());
+    }
+
+    let mut link = link_paths
+        .iter()
+        .map(|config_path| open_pci_config(config_path, true))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // Bits that do not read back are found below, once per function rather than every round.
+    match apply_link(&args.options, &args.source, &mut link, output) {
+        Err(err) if err.context != "verify" => return Err(err),
+        _ => {}
+    }
+
+    for config_path in link_paths {
+        let config = open_pci_config(config_path, false)?;
+        let plan = plan_aspm(&args.options.request, &config)?;
+        let stuck = stuck_bits.entry(config.path.clone()).or_default();
+        let (link_control_bits, l1ss_control_bits) = stuck.changes(&plan);
+
+        if link_control_bits != 0 {
+            stuck.link_control |= link_control_bits;
+
+            report_warning(
+                output,
+                format!(
+                    "{}: link control reads 0x{:04x} instead of 0x{:04x} ({}), no longer restoring those bits",
+                    config.path,
+                    plan.link_control_old_value,
+                    plan.link_control_new_value,
+                    bit_difference_names(
+                        (plan.link_control_new_value & link_control_bits) as u32,
+                        (plan.link_control_old_value & link_control_bits) as u32,
+                        &PCI_EXP_LNKCTL_NAMES
+                    )
+                ),
+            );
+        }
+
+        if l1ss_control_bits != 0 {
+            stuck.l1ss_control |= l1ss_control_bits;
+
+            report_warning(
+                output,
+                format!(
+                    "{}: l1 pm substates control reads 0x{:08x} instead of 0x{:08x} ({}), no longer restoring those bits",
+                    config.path,
+                    plan.l1ss_control_old_value,
+                    plan.l1ss_control_new_value,
+                    bit_difference_names(
+                        plan.l1ss_control_new_value & l1ss_control_bits,
+                        plan.l1ss_control_old_value & l1ss_control_bits,
+                        &PCI_L1SS_CTL1_NAMES
+                    )
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the warnings of a watch round as they come, since the JSON document at exit never does.
+fn flush_watch_output(output: &mut Output) {
+    if output.format == Format::Json {
+        if let Some(policy) = output.policy.take() {
+            println!("{}", Json::Object(vec![("policy", policy)]));
+        }
+
+        for warning in output.warnings.drain(..) {
+            println!("{}", Json::Object(vec![("warning", warning.into())]));
+        }
+    }
+
+    // The corrections were already reported as they were found.
+    output.devices.clear();
+}
+
+fn run_watch(args: &Args, output: &mut Output) -> Result<(), Error> {
+    let new_policy = check_kernel_policy(args, output)?;
+    check_firmware_aspm(&args.options, &args.source, output)?;
+
+    if let Some(policy) = new_policy {
+        write_kernel_policy(policy, output)?;
+    }
+
+    flush_watch_output(output);
+
+    let mut stuck_bits = std::collections::BTreeMap::new();
+
+    // Devices come and go while we watch, so failures are reported and retried next round.
+    loop {
+        match watch_link_paths(args, output) {
+            Ok(links) => {
+                for link_paths in links {
+                    if let Err(err) = watch_link(args, &link_paths, &mut stuck_bits, output) {
+                        report_warning(output, err.to_string());
+                    }
+                }
+            }
+            Err(err) => report_warning(output, err.to_string()),
+        }
+
+        flush_watch_output(output);
+
+        std::thread::sleep(args.interval);
+    }
+}
+
+fn generated_state_file(args: &Args) -> &str {
+    // /var may still be read-only when udev adds a device early in boot, and the saved
+    // values only describe the running system anyway.
+    if args.options.state_file == DEFAULT_STATE_FILE {
+        GENERATED_STATE_FILE
+    } else {
+        &args.options.state_file
+    }
+}
+
+/// First lines of every generated file, with the command that undoes what it applies.
+fn generated_header(args: &Args) -> String {
+    format!(
+        "# Generated by aspmctl generate; do not edit.\n\
+         # Restore the original settings with: {} restore --state-file={}\n",
+        args.binary,
+        generated_state_file(args)
+    )
+}
+
+/// The options that reproduce the requested configuration, in a fixed order.
+fn command_arguments(args: &Args) -> Vec<String> {
+    let action = |enable: bool| if enable { "enable" } else { "disable" };
+    let mut arguments = Vec::new();
+
+    for (bit, name) in LINK_CONTROL_OPTION_NAMES {
+        if args.options.request.mask & bit != 0 {
+            arguments.push(format!(
+                "--{}-{}",
+                action(args.options.request.flags & bit != 0),
+                name
+            ));
+        }
+    }
+
+    for (bit, name) in L1SS_OPTION_NAMES {
+        if args.options.request.l1ss_mask & bit != 0 {
+            arguments.push(format!(
+                "--{}-{}",
+                action(args.options.request.l1ss_flags & bit != 0),
+                name
+            ));
+        }
+    }
+
+    if let Some(enable) = args.options.ltr.enable {
+        arguments.push(format!("--{}-ltr", action(enable)));
+    }
+
+    let ltr_latencies = [
+        (
+            "--ltr-max-snoop-latency",
+            args.options.ltr.max_snoop_latency,
+        ),
+        (
+            "--ltr-max-no-snoop-latency",
+            args.options.ltr.max_no_snoop_latency,
+        ),
+    ];
+
+    for (option, value) in ltr_latencies {
+        if let Some(latency_ns) = value.and_then(ltr_latency_ns) {
+            arguments.push(format!("{}={}", option, latency_ns));
+        }
+    }
+
+    if args.options.program_l12_timing {
+        arguments.push("--program-l1.2-timing".to_string());
+    }
+
+    if args.options.common_clock {
+        arguments.push("--common-clock".to_string());
+    }
+
+    if args.options.force {
+        arguments.push("--force".to_string());
+    }
+
+    if args.options.ignore_firmware {
+        arguments.push("--ignore-firmware".to_string());
+    }
+
+    if args.options.backend != Backend::Auto {
+        arguments.push(format!("--backend={}", args.options.backend.name()));
+    }
+
+    arguments.push(format!("--state-file={}", generated_state_file(args)));
+
+    if args.options.acpi_tables != SYSFS_ACPI_TABLES {
+        arguments.push(format!("--acpi-tables={}", args.options.acpi_tables));
+    }
+
+    arguments
+}
+
+fn command_line(binary: &str, arguments: &[String], target: &str) -> String {
+    std::iter::once(binary)
+        .chain(arguments.iter().map(String::as_str))
+        .chain(std::iter::once(target))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn generate_udev_rules(args: &Args, arguments: &[String]) -> String {
+    let mut rules = generated_header(args);
+
+    if args.all {
+        // A new function completes the link above it, which is the one to configure.
+        rules.push_str(&format!(
+            "ACTION==\"add\", SUBSYSTEM==\"pci\", RUN+=\"{}\"\n",
+            command_line(&args.binary, arguments, "%k")
+        ));
+    }
+
+    for selector in &args.selectors {
+        rules.push_str(&format!(
+            "ACTION==\"add\", SUBSYSTEM==\"pci\", KERNEL==\"{}\", RUN+=\"{}\"\n",
+            selector,
+            command_line(&args.binary, arguments, selector)
+        ));
+    }
+
+    rules
+}
+
+fn generate_systemd_unit(args: &Args, arguments: &[String]) -> String {
+    let mut unit = generated_header(args);
+
+    unit.push_str(
+        "[Unit]\n\
+         Description=Apply PCI Express ASPM settings\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n",
+    );
+
+    if let Some(policy) = args.set_policy {
+        unit.push_str(&format!(
+            "ExecStart={} --set-policy={}\n",
+            args.binary, policy
+        ));
+    }
+
+    if args.all {
+        unit.push_str(&format!(
+            "ExecStart={}\n",
+            command_line(&args.binary, arguments, "--all")
+        ));
+    }
+
+    for selector in &args.selectors {
+        unit.push_str(&format!(
+            "ExecStart={}\n",
+            command_line(&args.binary, arguments, selector)
+        ));
+    }
+
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+
+    unit
+}
+
+fn run_generate(args: &Args, output: &mut Output) -> Result<(), Error> {
+    let arguments = command_arguments(args);
+
+    // Neither udev nor systemd would split these the way the shell does.
+    if let Some(argument) = std::iter::once(&args.binary)
+        .chain(&arguments)
+        .find(|argument| argument.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\'))
+    {
+        return Err(Error::new(
+            "error",
+            argument,
+            "unable to quote argument for udev and systemd",
+        ));
+    }
+
+    let files = [
+        (UDEV_RULES_FILE, generate_udev_rules(args, &arguments)),
+        (SYSTEMD_UNIT_FILE, generate_systemd_unit(args, &arguments)),
+    ];
+
+    let file_path = |name: &str| {
+        args.output_dir
+            .as_ref()
+            .map(|output_dir| std::path::Path::new(output_dir).join(name))
+    };
+
+    for (name, contents) in &files {
+        if let Some(path) = file_path(name) {
+            std::fs::write(&path, contents)
+                .map_err(|err| Error::new("write", &path.to_string_lossy(), err))?;
+        }
+    }
+
+    match output.format {
+        Format::Json => {
+            output.files = Some(
+                files
+                    .iter()
+                    .map(|(name, contents)| {
+                        Json::Object(vec![
+                            ("name", (*name).into()),
+                            (
+                                "path",
+                                file_path(name)
+                                    .map(|path| path.to_string_lossy().into_owned())
+                                    .into(),
+                            ),
+                            ("contents", contents.as_str().into()),
+                        ])
+                    })
+                    .collect(),
+            );
+        }
+        Format::Text if args.output_dir.is_none() => {
+            for (index, (name, contents)) in files.iter().enumerate() {
+                if index != 0 {
+                    println!();
+                }
+
+                println!("# {}", name);
+                print!("{}", contents);
+            }
+        }
+        Format::Text => {}
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(value) => value,
+        Err(err) => {
+            let output = Output {
+                format: requested_format(),
+                ..Default::default()
+            };
+            return finish_output(output, Err(err));
+        }
+    };
+
+    let mut output = Output {
+        format: args.format,
+        ..Default::default()
+    };
+
+    let result = match (&args.mode, &args.device) {
+        (Mode::Restore, _) => run_restore(&args, &mut output),
+        (Mode::Apply, device) => run_apply(&args, device.as_deref(), &mut output),
+        (Mode::Status, Some(device)) => run_device(&args, device, &mut output),
+        (Mode::Status, None) => run_all(&args, &mut output),
+        (Mode::Watch, _) => run_watch(&args, &mut output),
+        (Mode::Generate, _) => run_generate(&args, &mut output),
+        (Mode::Dump, _) => run_dump(&args, &mut output),
+    };
+
+    finish_output(output, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(link_control_values: (u16, u16), l1ss_control_values: (u32, u32)) -> AspmPlan {
+        AspmPlan {
+            link_capabilities_value: 0,
+            link_control_offset: 0x50,
+            link_control_old_value: link_control_values.0,
+            link_control_new_value: link_control_values.1,
+            l1ss_control_offset: Some(0x108),
+            l1ss_control_old_value: l1ss_control_values.0,
+            l1ss_control_new_value: l1ss_control_values.1,
+            l1ss_control2_old_value: 0,
+            l1ss_control2_new_value: 0,
+            l12_timing: None,
+        }
+    }
+
+    #[test]
+    fn stuck_bits_leave_the_other_changes() {
+        let drifted = plan(
+            (0, PCI_EXP_LNKCTL_ASPMC),
+            (0, PCI_L1SS_CTL1_ASPM_L1_1 | PCI_L1SS_CTL1_ASPM_L1_2),
+        );
+        let mut stuck = StuckBits::default();
+
+        assert_eq!(
+            stuck.changes(&drifted),
+            (
+                PCI_EXP_LNKCTL_ASPMC,
+                PCI_L1SS_CTL1_ASPM_L1_1 | PCI_L1SS_CTL1_ASPM_L1_2
+            )
+        );
+
+        stuck.link_control |= PCI_EXP_LNKCTL_ASPM_L0S;
+        stuck.l1ss_control |= PCI_L1SS_CTL1_ASPM_L1_2;
+
+        assert_eq!(
+            stuck.changes(&drifted),
+            (PCI_EXP_LNKCTL_ASPM_L1, PCI_L1SS_CTL1_ASPM_L1_1)
+        );
+
+        // A stuck bit no longer counts as drift, whichever way it is stuck.
+        let reverted = plan((PCI_EXP_LNKCTL_ASPM_L0S, 0), (0, 0));
+
+        assert_eq!(stuck.changes(&reverted), (0, 0));
+    }
 }
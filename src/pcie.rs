@@ -0,0 +1,766 @@
+use crate::capability::find_pci_exp_capability;
+use crate::config::{field_get, read_config_u16, read_config_u32};
+use crate::decode::{aspm_control_name, link_capabilities_aspm_support};
+use crate::error::Error;
+use crate::regs::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciePortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamPort,
+    DownstreamPort,
+    PciBridge,
+    PcieBridge,
+    RootComplexEndpoint,
+    RootComplexEventCollector,
+    Unknown(u16),
+}
+
+impl From<u16> for PciePortType {
+    fn from(port_type: u16) -> PciePortType {
+        match port_type {
+            PCI_EXP_TYPE_ENDPOINT => PciePortType::Endpoint,
+            PCI_EXP_TYPE_LEG_END => PciePortType::LegacyEndpoint,
+            PCI_EXP_TYPE_ROOT_PORT => PciePortType::RootPort,
+            PCI_EXP_TYPE_UPSTREAM => PciePortType::UpstreamPort,
+            PCI_EXP_TYPE_DOWNSTREAM => PciePortType::DownstreamPort,
+            PCI_EXP_TYPE_PCI_BRIDGE => PciePortType::PciBridge,
+            PCI_EXP_TYPE_PCIE_BRIDGE => PciePortType::PcieBridge,
+            PCI_EXP_TYPE_RC_END => PciePortType::RootComplexEndpoint,
+            PCI_EXP_TYPE_RC_EC => PciePortType::RootComplexEventCollector,
+            port_type => PciePortType::Unknown(port_type),
+        }
+    }
+}
+
+impl std::fmt::Display for PciePortType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PciePortType::Endpoint => write!(f, "Endpoint"),
+            PciePortType::LegacyEndpoint => write!(f, "Legacy Endpoint"),
+            PciePortType::RootPort => write!(f, "Root Port"),
+            PciePortType::UpstreamPort => write!(f, "Upstream Port"),
+            PciePortType::DownstreamPort => write!(f, "Downstream Port"),
+            PciePortType::PciBridge => write!(f, "PCI-Express to PCI/PCI-X Bridge"),
+            PciePortType::PcieBridge => write!(f, "PCI/PCI-X to PCI-Express Bridge"),
+            PciePortType::RootComplexEndpoint => write!(f, "Root Complex Integrated Endpoint"),
+            PciePortType::RootComplexEventCollector => write!(f, "Root Complex Event Collector"),
+            PciePortType::Unknown(port_type) => write!(f, "Unknown type {}", port_type),
+        }
+    }
+}
+
+/// Upper bound of an L0s latency encoding in nanoseconds, `None` for the open-ended encoding.
+pub fn l0s_latency_ns(encoding: u32) -> Option<u32> {
+    (encoding < 7).then(|| 64 << encoding)
+}
+
+/// Upper bound of an L1 latency encoding in nanoseconds, `None` for the open-ended encoding.
+pub fn l1_latency_ns(encoding: u32) -> Option<u32> {
+    (encoding < 7).then(|| 1000 << encoding)
+}
+
+pub fn link_speed_name(speed: u32) -> &'static str {
+    match speed {
+        1 => "2.5GT/s",
+        2 => "5GT/s",
+        3 => "8GT/s",
+        4 => "16GT/s",
+        5 => "32GT/s",
+        6 => "64GT/s",
+        _ => "unknown",
+    }
+}
+
+fn format_latency(latency_ns: Option<u32>, open_ended: &str) -> String {
+    match latency_ns {
+        Some(latency_ns) if latency_ns < 1000 => format!("<{}ns", latency_ns),
+        Some(latency_ns) => format!("<{}us", latency_ns / 1000),
+        None => open_ended.to_string(),
+    }
+}
+
+fn format_power_limit(value: u32, scale: u32) -> String {
+    let milliwatts = value * [1000, 100, 10, 1][scale as usize];
+
+    format!("{}W", milliwatts as f64 / 1000.0)
+}
+
+fn flag(name: &str, value: bool) -> String {
+    format!("{}{}", name, if value { '+' } else { '-' })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceCapabilities {
+    pub value: u32,
+    pub max_payload_size: u32,
+    pub phantom_functions: u32,
+    pub extended_tag: bool,
+    pub l0s_acceptable_latency_ns: Option<u32>,
+    pub l1_acceptable_latency_ns: Option<u32>,
+    pub role_based_error: bool,
+    pub slot_power_limit_value: u32,
+    pub slot_power_limit_scale: u32,
+    pub function_level_reset: bool,
+}
+
+impl From<u32> for DeviceCapabilities {
+    fn from(value: u32) -> DeviceCapabilities {
+        DeviceCapabilities {
+            value,
+            max_payload_size: 128 << field_get(value, PCI_EXP_DEVCAP_PAYLOAD),
+            phantom_functions: field_get(value, PCI_EXP_DEVCAP_PHANTOM),
+            extended_tag: value & PCI_EXP_DEVCAP_EXT_TAG != 0,
+            l0s_acceptable_latency_ns: l0s_latency_ns(field_get(value, PCI_EXP_DEVCAP_L0S)),
+            l1_acceptable_latency_ns: l1_latency_ns(field_get(value, PCI_EXP_DEVCAP_L1)),
+            role_based_error: value & PCI_EXP_DEVCAP_RBER != 0,
+            slot_power_limit_value: field_get(value, PCI_EXP_DEVCAP_PWR_VAL),
+            slot_power_limit_scale: field_get(value, PCI_EXP_DEVCAP_PWR_SCL),
+            function_level_reset: value & PCI_EXP_DEVCAP_FLR != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "MaxPayload {} bytes, PhantFunc {}, Latency L0s {}, L1 {}, {} {} {}, SlotPowerLimit {}",
+            self.max_payload_size,
+            self.phantom_functions,
+            format_latency(self.l0s_acceptable_latency_ns, "unlimited"),
+            format_latency(self.l1_acceptable_latency_ns, "unlimited"),
+            flag("ExtTag", self.extended_tag),
+            flag("RBE", self.role_based_error),
+            flag("FLReset", self.function_level_reset),
+            format_power_limit(self.slot_power_limit_value, self.slot_power_limit_scale)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceControl {
+    pub value: u16,
+    pub correctable_error_reporting: bool,
+    pub non_fatal_error_reporting: bool,
+    pub fatal_error_reporting: bool,
+    pub unsupported_request_reporting: bool,
+    pub relaxed_ordering: bool,
+    pub max_payload_size: u32,
+    pub extended_tag: bool,
+    pub phantom_functions: bool,
+    pub aux_power_pm: bool,
+    pub no_snoop: bool,
+    pub max_read_request_size: u32,
+    pub bridge_retry_or_flr: bool,
+}
+
+impl From<u16> for DeviceControl {
+    fn from(value: u16) -> DeviceControl {
+        DeviceControl {
+            value,
+            correctable_error_reporting: value & PCI_EXP_DEVCTL_CERE != 0,
+            non_fatal_error_reporting: value & PCI_EXP_DEVCTL_NFERE != 0,
+            fatal_error_reporting: value & PCI_EXP_DEVCTL_FERE != 0,
+            unsupported_request_reporting: value & PCI_EXP_DEVCTL_URRE != 0,
+            relaxed_ordering: value & PCI_EXP_DEVCTL_RELAX_EN != 0,
+            max_payload_size: 128 << field_get(value as u32, PCI_EXP_DEVCTL_PAYLOAD as u32),
+            extended_tag: value & PCI_EXP_DEVCTL_EXT_TAG != 0,
+            phantom_functions: value & PCI_EXP_DEVCTL_PHANTOM != 0,
+            aux_power_pm: value & PCI_EXP_DEVCTL_AUX_PME != 0,
+            no_snoop: value & PCI_EXP_DEVCTL_NOSNOOP_EN != 0,
+            max_read_request_size: 128 << field_get(value as u32, PCI_EXP_DEVCTL_READRQ as u32),
+            bridge_retry_or_flr: value & PCI_EXP_DEVCTL_BCR_FLR != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}, {} {} {} {} {}, MaxPayload {} bytes, MaxReadReq {} bytes",
+            flag("CorrErr", self.correctable_error_reporting),
+            flag("NonFatalErr", self.non_fatal_error_reporting),
+            flag("FatalErr", self.fatal_error_reporting),
+            flag("UnsupReq", self.unsupported_request_reporting),
+            flag("RlxdOrd", self.relaxed_ordering),
+            flag("ExtTag", self.extended_tag),
+            flag("PhantFunc", self.phantom_functions),
+            flag("AuxPwr", self.aux_power_pm),
+            flag("NoSnoop", self.no_snoop),
+            self.max_payload_size,
+            self.max_read_request_size
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceStatus {
+    pub value: u16,
+    pub correctable_error: bool,
+    pub non_fatal_error: bool,
+    pub fatal_error: bool,
+    pub unsupported_request: bool,
+    pub aux_power: bool,
+    pub transactions_pending: bool,
+}
+
+impl From<u16> for DeviceStatus {
+    fn from(value: u16) -> DeviceStatus {
+        DeviceStatus {
+            value,
+            correctable_error: value & PCI_EXP_DEVSTA_CED != 0,
+            non_fatal_error: value & PCI_EXP_DEVSTA_NFED != 0,
+            fatal_error: value & PCI_EXP_DEVSTA_FED != 0,
+            unsupported_request: value & PCI_EXP_DEVSTA_URD != 0,
+            aux_power: value & PCI_EXP_DEVSTA_AUXPD != 0,
+            transactions_pending: value & PCI_EXP_DEVSTA_TRPND != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            flag("CorrErr", self.correctable_error),
+            flag("NonFatalErr", self.non_fatal_error),
+            flag("FatalErr", self.fatal_error),
+            flag("UnsupReq", self.unsupported_request),
+            flag("AuxPwr", self.aux_power),
+            flag("TransPend", self.transactions_pending)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkCapabilities {
+    pub value: u32,
+    pub max_link_speed: u32,
+    pub max_link_width: u32,
+    pub aspm_support: u16,
+    pub l0s_exit_latency_ns: Option<u32>,
+    pub l1_exit_latency_ns: Option<u32>,
+    pub clock_power_management: bool,
+    pub surprise_down_reporting: bool,
+    pub data_link_layer_active_reporting: bool,
+    pub link_bandwidth_notification: bool,
+    pub aspm_optionality_compliance: bool,
+    pub port_number: u32,
+}
+
+impl From<u32> for LinkCapabilities {
+    fn from(value: u32) -> LinkCapabilities {
+        LinkCapabilities {
+            value,
+            max_link_speed: field_get(value, PCI_EXP_LNKCAP_SLS),
+            max_link_width: field_get(value, PCI_EXP_LNKCAP_MLW),
+            aspm_support: link_capabilities_aspm_support(value),
+            l0s_exit_latency_ns: l0s_latency_ns(field_get(value, PCI_EXP_LNKCAP_L0SEL)),
+            l1_exit_latency_ns: l1_latency_ns(field_get(value, PCI_EXP_LNKCAP_L1EL)),
+            clock_power_management: value & PCI_EXP_LNKCAP_CLKPM != 0,
+            surprise_down_reporting: value & PCI_EXP_LNKCAP_SDERC != 0,
+            data_link_layer_active_reporting: value & PCI_EXP_LNKCAP_DLLLARC != 0,
+            link_bandwidth_notification: value & PCI_EXP_LNKCAP_LBNC != 0,
+            aspm_optionality_compliance: value & PCI_EXP_LNKCAP_ASPM_COMP != 0,
+            port_number: field_get(value, PCI_EXP_LNKCAP_PN),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Port #{}, Speed {}, Width x{}, ASPM {}, Exit Latency L0s {}, L1 {}, {} {} {} {} {}",
+            self.port_number,
+            link_speed_name(self.max_link_speed),
+            self.max_link_width,
+            aspm_control_name(self.aspm_support),
+            format_latency(self.l0s_exit_latency_ns, ">4us"),
+            format_latency(self.l1_exit_latency_ns, ">64us"),
+            flag("ClockPM", self.clock_power_management),
+            flag("Surprise", self.surprise_down_reporting),
+            flag("LLActRep", self.data_link_layer_active_reporting),
+            flag("BwNot", self.link_bandwidth_notification),
+            flag("ASPMOptComp", self.aspm_optionality_compliance)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkControl {
+    pub value: u16,
+    pub aspm_control: u16,
+    pub read_completion_boundary_128: bool,
+    pub link_disable: bool,
+    pub common_clock: bool,
+    pub extended_synch: bool,
+    pub clock_power_management: bool,
+    pub autonomous_width_disable: bool,
+    pub bandwidth_management_interrupt: bool,
+    pub autonomous_bandwidth_interrupt: bool,
+}
+
+impl From<u16> for LinkControl {
+    fn from(value: u16) -> LinkControl {
+        LinkControl {
+            value,
+            aspm_control: value & PCI_EXP_LNKCTL_ASPMC,
+            read_completion_boundary_128: value & PCI_EXP_LNKCTL_RCB != 0,
+            link_disable: value & PCI_EXP_LNKCTL_LD != 0,
+            common_clock: value & PCI_EXP_LNKCTL_CCC != 0,
+            extended_synch: value & PCI_EXP_LNKCTL_ES != 0,
+            clock_power_management: value & PCI_EXP_LNKCTL_CLKREQ_EN != 0,
+            autonomous_width_disable: value & PCI_EXP_LNKCTL_HAWD != 0,
+            bandwidth_management_interrupt: value & PCI_EXP_LNKCTL_LBMIE != 0,
+            autonomous_bandwidth_interrupt: value & PCI_EXP_LNKCTL_LABIE != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for LinkControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ASPM {}, RCB {} bytes, {} {} {} {} {} {} {}",
+            aspm_control_name(self.aspm_control),
+            if self.read_completion_boundary_128 {
+                128
+            } else {
+                64
+            },
+            flag("LnkDisable", self.link_disable),
+            flag("CommClk", self.common_clock),
+            flag("ExtSynch", self.extended_synch),
+            flag("ClockPM", self.clock_power_management),
+            flag("AutWidDis", self.autonomous_width_disable),
+            flag("BWInt", self.bandwidth_management_interrupt),
+            flag("AutBWInt", self.autonomous_bandwidth_interrupt)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkStatus {
+    pub value: u16,
+    pub current_link_speed: u32,
+    pub negotiated_link_width: u32,
+    pub link_training: bool,
+    pub slot_clock: bool,
+    pub data_link_layer_active: bool,
+    pub bandwidth_management_status: bool,
+    pub autonomous_bandwidth_status: bool,
+}
+
+impl From<u16> for LinkStatus {
+    fn from(value: u16) -> LinkStatus {
+        LinkStatus {
+            value,
+            current_link_speed: field_get(value as u32, PCI_EXP_LNKSTA_CLS as u32),
+            negotiated_link_width: field_get(value as u32, PCI_EXP_LNKSTA_NLW as u32),
+            link_training: value & PCI_EXP_LNKSTA_LT != 0,
+            slot_clock: value & PCI_EXP_LNKSTA_SLC != 0,
+            data_link_layer_active: value & PCI_EXP_LNKSTA_DLLLA != 0,
+            bandwidth_management_status: value & PCI_EXP_LNKSTA_LBMS != 0,
+            autonomous_bandwidth_status: value & PCI_EXP_LNKSTA_LABS != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Speed {}, Width x{}, {} {} {} {} {}",
+            link_speed_name(self.current_link_speed),
+            self.negotiated_link_width,
+            flag("Train", self.link_training),
+            flag("SlotClk", self.slot_clock),
+            flag("DLActive", self.data_link_layer_active),
+            flag("BWMgmt", self.bandwidth_management_status),
+            flag("ABWMgmt", self.autonomous_bandwidth_status)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlotCapabilities {
+    pub value: u32,
+    pub attention_button: bool,
+    pub power_controller: bool,
+    pub mrl_sensor: bool,
+    pub attention_indicator: bool,
+    pub power_indicator: bool,
+    pub hot_plug_surprise: bool,
+    pub hot_plug_capable: bool,
+    pub slot_power_limit_value: u32,
+    pub slot_power_limit_scale: u32,
+    pub electromechanical_interlock: bool,
+    pub no_command_completed: bool,
+    pub physical_slot_number: u32,
+}
+
+impl From<u32> for SlotCapabilities {
+    fn from(value: u32) -> SlotCapabilities {
+        SlotCapabilities {
+            value,
+            attention_button: value & PCI_EXP_SLTCAP_ABP != 0,
+            power_controller: value & PCI_EXP_SLTCAP_PCP != 0,
+            mrl_sensor: value & PCI_EXP_SLTCAP_MRLSP != 0,
+            attention_indicator: value & PCI_EXP_SLTCAP_AIP != 0,
+            power_indicator: value & PCI_EXP_SLTCAP_PIP != 0,
+            hot_plug_surprise: value & PCI_EXP_SLTCAP_HPS != 0,
+            hot_plug_capable: value & PCI_EXP_SLTCAP_HPC != 0,
+            slot_power_limit_value: field_get(value, PCI_EXP_SLTCAP_SPLV),
+            slot_power_limit_scale: field_get(value, PCI_EXP_SLTCAP_SPLS),
+            electromechanical_interlock: value & PCI_EXP_SLTCAP_EIP != 0,
+            no_command_completed: value & PCI_EXP_SLTCAP_NCCS != 0,
+            physical_slot_number: field_get(value, PCI_EXP_SLTCAP_PSN),
+        }
+    }
+}
+
+impl std::fmt::Display for SlotCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} {}, Slot #{}, PowerLimit {}, {} {}",
+            flag("AttnBtn", self.attention_button),
+            flag("PwrCtrl", self.power_controller),
+            flag("MRL", self.mrl_sensor),
+            flag("AttnInd", self.attention_indicator),
+            flag("PwrInd", self.power_indicator),
+            flag("HotPlug", self.hot_plug_capable),
+            flag("Surprise", self.hot_plug_surprise),
+            self.physical_slot_number,
+            format_power_limit(self.slot_power_limit_value, self.slot_power_limit_scale),
+            flag("Interlock", self.electromechanical_interlock),
+            flag("NoCompl", self.no_command_completed)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceCapabilities2 {
+    pub value: u32,
+    pub completion_timeout_ranges: u32,
+    pub completion_timeout_disable: bool,
+    pub ari_forwarding: bool,
+    pub atomic_op_routing: bool,
+    pub atomic_op_32bit_completer: bool,
+    pub atomic_op_64bit_completer: bool,
+    pub atomic_op_128bit_cas_completer: bool,
+    pub latency_tolerance_reporting: bool,
+    pub obff: u32,
+}
+
+impl From<u32> for DeviceCapabilities2 {
+    fn from(value: u32) -> DeviceCapabilities2 {
+        DeviceCapabilities2 {
+            value,
+            completion_timeout_ranges: field_get(value, PCI_EXP_DEVCAP2_COMP_TMOUT_RANGES),
+            completion_timeout_disable: value & PCI_EXP_DEVCAP2_COMP_TMOUT_DIS != 0,
+            ari_forwarding: value & PCI_EXP_DEVCAP2_ARI != 0,
+            atomic_op_routing: value & PCI_EXP_DEVCAP2_ATOMIC_ROUTE != 0,
+            atomic_op_32bit_completer: value & PCI_EXP_DEVCAP2_ATOMIC_COMP32 != 0,
+            atomic_op_64bit_completer: value & PCI_EXP_DEVCAP2_ATOMIC_COMP64 != 0,
+            atomic_op_128bit_cas_completer: value & PCI_EXP_DEVCAP2_ATOMIC_COMP128 != 0,
+            latency_tolerance_reporting: value & PCI_EXP_DEVCAP2_LTR != 0,
+            obff: field_get(value, PCI_EXP_DEVCAP2_OBFF_MASK),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceCapabilities2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let completion_timeout_ranges: String = ['A', 'B', 'C', 'D']
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| self.completion_timeout_ranges & (1 << index) != 0)
+            .map(|(_, range)| range)
+            .collect();
+
+        let obff = match self.obff {
+            0 => "Not Supported",
+            1 => "Via message",
+            2 => "Via WAKE#",
+            _ => "Via message/WAKE#",
+        };
+
+        write!(
+            f,
+            "Completion Timeout: Range {}, {}, {} {}, OBFF {}, AtomicOpsCap: {} {} {} {}",
+            if completion_timeout_ranges.is_empty() {
+                "Not Supported".to_string()
+            } else {
+                completion_timeout_ranges
+            },
+            flag("TimeoutDis", self.completion_timeout_disable),
+            flag("LTR", self.latency_tolerance_reporting),
+            flag("ARIFwd", self.ari_forwarding),
+            obff,
+            flag("Routing", self.atomic_op_routing),
+            flag("32bit", self.atomic_op_32bit_completer),
+            flag("64bit", self.atomic_op_64bit_completer),
+            flag("128bitCAS", self.atomic_op_128bit_cas_completer)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkCapabilities2 {
+    pub value: u32,
+    pub supported_link_speeds: u32,
+    pub crosslink: bool,
+}
+
+impl LinkCapabilities2 {
+    pub fn supported_link_speed_names(&self) -> Vec<&'static str> {
+        (1..=7)
+            .filter(|speed| self.supported_link_speeds & (1 << (speed - 1)) != 0)
+            .map(link_speed_name)
+            .collect()
+    }
+}
+
+impl From<u32> for LinkCapabilities2 {
+    fn from(value: u32) -> LinkCapabilities2 {
+        LinkCapabilities2 {
+            value,
+            supported_link_speeds: field_get(value, PCI_EXP_LNKCAP2_SLS),
+            crosslink: value & PCI_EXP_LNKCAP2_CROSSLINK != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for LinkCapabilities2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Supported Link Speeds: {}, {}",
+            match self.supported_link_speed_names() {
+                names if names.is_empty() => "none".to_string(),
+                names => names.join(" "),
+            },
+            flag("Crosslink", self.crosslink)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkControl2 {
+    pub value: u16,
+    pub target_link_speed: u32,
+    pub enter_compliance: bool,
+    pub hardware_autonomous_speed_disable: bool,
+    pub selectable_de_emphasis: bool,
+    pub transmit_margin: u32,
+}
+
+impl From<u16> for LinkControl2 {
+    fn from(value: u16) -> LinkControl2 {
+        LinkControl2 {
+            value,
+            target_link_speed: field_get(value as u32, PCI_EXP_LNKCTL2_TLS as u32),
+            enter_compliance: value & PCI_EXP_LNKCTL2_ENTER_COMP != 0,
+            hardware_autonomous_speed_disable: value & PCI_EXP_LNKCTL2_HASD != 0,
+            selectable_de_emphasis: value & PCI_EXP_LNKCTL2_SELECTABLE_DEEMPH != 0,
+            transmit_margin: field_get(value as u32, PCI_EXP_LNKCTL2_TX_MARGIN as u32),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkControl2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Target Link Speed: {}, {} {}, Selectable De-emphasis: {}, Transmit Margin: {}",
+            link_speed_name(self.target_link_speed),
+            flag("EnterCompliance", self.enter_compliance),
+            flag("SpeedDis", self.hardware_autonomous_speed_disable),
+            if self.selectable_de_emphasis {
+                "-3.5dB"
+            } else {
+                "-6dB"
+            },
+            self.transmit_margin
+        )
+    }
+}
+
+/// The PCI Express Capability structure decoded register by register.
+#[derive(Debug, Clone, Copy)]
+pub struct PcieCapability {
+    pub offset: usize,
+    pub flags: u16,
+    pub version: u16,
+    pub port_type: PciePortType,
+    pub slot_implemented: bool,
+    pub interrupt_message_number: u16,
+    pub device_capabilities: DeviceCapabilities,
+    pub device_control: DeviceControl,
+    pub device_status: DeviceStatus,
+    pub link_capabilities: LinkCapabilities,
+    pub link_control: LinkControl,
+    pub link_status: LinkStatus,
+    pub slot_capabilities: Option<SlotCapabilities>,
+    pub device_capabilities2: Option<DeviceCapabilities2>,
+    pub link_capabilities2: Option<LinkCapabilities2>,
+    pub link_control2: Option<LinkControl2>,
+}
+
+pub fn decode_pcie_capability(config_buffer: &[u8]) -> Result<PcieCapability, Error> {
+    let offset = find_pci_exp_capability(config_buffer)?.start;
+    let flags = read_config_u16(config_buffer, offset + PCI_EXP_FLAGS);
+    let version = flags & PCI_EXP_FLAGS_VERS;
+    let slot_implemented = flags & PCI_EXP_FLAGS_SLOT != 0;
+
+    Ok(PcieCapability {
+        offset,
+        flags,
+        version,
+        port_type: PciePortType::from((flags & PCI_EXP_FLAGS_TYPE) >> 4),
+        slot_implemented,
+        interrupt_message_number: (flags & PCI_EXP_FLAGS_IRQ) >> 9,
+        device_capabilities: read_config_u32(config_buffer, offset + PCI_EXP_DEVCAP).into(),
+        device_control: read_config_u16(config_buffer, offset + PCI_EXP_DEVCTL).into(),
+        device_status: read_config_u16(config_buffer, offset + PCI_EXP_DEVSTA).into(),
+        link_capabilities: read_config_u32(config_buffer, offset + PCI_EXP_LNKCAP).into(),
+        link_control: read_config_u16(config_buffer, offset + PCI_EXP_LNKCTL).into(),
+        link_status: read_config_u16(config_buffer, offset + PCI_EXP_LNKSTA).into(),
+        slot_capabilities: slot_implemented
+            .then(|| read_config_u32(config_buffer, offset + PCI_EXP_SLTCAP).into()),
+        device_capabilities2: (version >= 2)
+            .then(|| read_config_u32(config_buffer, offset + PCI_EXP_DEVCAP2).into()),
+        link_capabilities2: (version >= 2)
+            .then(|| read_config_u32(config_buffer, offset + PCI_EXP_LNKCAP2).into()),
+        link_control2: (version >= 2)
+            .then(|| read_config_u16(config_buffer, offset + PCI_EXP_LNKCTL2).into()),
+    })
+}
+
+impl std::fmt::Display for PcieCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Express (v{}) {}, {}, IntMsgNum {}",
+            self.version,
+            self.port_type,
+            flag("Slot", self.slot_implemented),
+            self.interrupt_message_number
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::*;
+
+    #[test]
+    fn latency_encodings_are_upper_bounds() {
+        assert_eq!(l0s_latency_ns(0), Some(64));
+        assert_eq!(l0s_latency_ns(6), Some(4096));
+        assert_eq!(l0s_latency_ns(7), None);
+        assert_eq!(l1_latency_ns(0), Some(1000));
+        assert_eq!(l1_latency_ns(6), Some(64000));
+        assert_eq!(l1_latency_ns(7), None);
+    }
+
+    #[test]
+    fn decode_pcie_capability_reads_every_register() {
+        let mut function = TestFunction::new(PCI_EXP_TYPE_ENDPOINT)
+            .link_capabilities(0x0005_ac43)
+            .link_control(PCI_EXP_LNKCTL_ASPM_L1 | PCI_EXP_LNKCTL_CCC)
+            .link_status(0x1043);
+
+        function.set_u32(TEST_PCIE_CAPABILITY + PCI_EXP_DEVCAP, 0x0000_8f81);
+        function.set_u32(TEST_PCIE_CAPABILITY + PCI_EXP_DEVCAP2, PCI_EXP_DEVCAP2_LTR);
+        function.set_u32(TEST_PCIE_CAPABILITY + PCI_EXP_LNKCAP2, 0x0000_000e);
+
+        let capability = decode_pcie_capability(&function.buffer).unwrap();
+
+        assert_eq!(capability.offset, TEST_PCIE_CAPABILITY);
+        assert_eq!(capability.version, 2);
+        assert_eq!(capability.port_type, PciePortType::Endpoint);
+        assert!(capability.slot_capabilities.is_none());
+
+        assert_eq!(capability.device_capabilities.max_payload_size, 256);
+        assert_eq!(
+            capability.device_capabilities.l0s_acceptable_latency_ns,
+            Some(4096)
+        );
+        assert_eq!(
+            capability.device_capabilities.l1_acceptable_latency_ns,
+            None
+        );
+
+        assert_eq!(capability.link_capabilities.max_link_speed, 3);
+        assert_eq!(capability.link_capabilities.max_link_width, 4);
+        assert_eq!(
+            capability.link_capabilities.aspm_support,
+            PCI_EXP_LNKCTL_ASPMC
+        );
+        assert_eq!(capability.link_capabilities.l0s_exit_latency_ns, Some(256));
+        assert_eq!(capability.link_capabilities.l1_exit_latency_ns, Some(8000));
+        assert!(capability.link_capabilities.clock_power_management);
+
+        assert_eq!(capability.link_control.aspm_control, PCI_EXP_LNKCTL_ASPM_L1);
+        assert!(capability.link_control.common_clock);
+        assert_eq!(capability.link_status.current_link_speed, 3);
+        assert_eq!(capability.link_status.negotiated_link_width, 4);
+        assert!(capability.link_status.slot_clock);
+
+        assert!(
+            capability
+                .device_capabilities2
+                .unwrap()
+                .latency_tolerance_reporting
+        );
+        assert_eq!(
+            capability
+                .link_capabilities2
+                .unwrap()
+                .supported_link_speed_names(),
+            ["2.5GT/s", "5GT/s", "8GT/s"]
+        );
+
+        assert_eq!(
+            capability.link_capabilities.to_string(),
+            "Port #0, Speed 8GT/s, Width x4, ASPM L0s L1, Exit Latency L0s <256ns, L1 <8us, \
+             ClockPM+ Surprise- LLActRep- BwNot- ASPMOptComp-"
+        );
+        assert_eq!(
+            capability.link_status.to_string(),
+            "Speed 8GT/s, Width x4, Train- SlotClk+ DLActive- BWMgmt- ABWMgmt-"
+        );
+    }
+
+    #[test]
+    fn decode_pcie_capability_follows_version_and_slot() {
+        let mut function = TestFunction::new(PCI_EXP_TYPE_ROOT_PORT);
+
+        function.set_u16(
+            TEST_PCIE_CAPABILITY + PCI_EXP_FLAGS,
+            1 | PCI_EXP_FLAGS_SLOT | (PCI_EXP_TYPE_ROOT_PORT << 4),
+        );
+
+        let capability = decode_pcie_capability(&function.buffer).unwrap();
+
+        assert_eq!(capability.version, 1);
+        assert_eq!(capability.port_type, PciePortType::RootPort);
+        assert!(capability.slot_capabilities.is_some());
+        assert!(capability.device_capabilities2.is_none());
+        assert!(capability.link_capabilities2.is_none());
+        assert!(capability.link_control2.is_none());
+        assert_eq!(
+            capability.to_string(),
+            "Express (v1) Root Port, Slot+, IntMsgNum 0"
+        );
+    }
+}
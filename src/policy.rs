@@ -0,0 +1,251 @@
+use crate::aspm::AspmRequest;
+use crate::decode::{aspm_control_name, l1ss_control_name, PCI_L1SS_CTL1_NAMES};
+use crate::error::Error;
+use crate::regs::*;
+
+pub const SYSFS_PCIE_ASPM_POLICY: &str = "/sys/module/pcie_aspm/parameters/policy";
+pub const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// The kernel's pcie_aspm policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AspmPolicy {
+    Default,
+    Performance,
+    Powersave,
+    Powersupersave,
+}
+
+impl AspmPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AspmPolicy::Default => "default",
+            AspmPolicy::Performance => "performance",
+            AspmPolicy::Powersave => "powersave",
+            AspmPolicy::Powersupersave => "powersupersave",
+        }
+    }
+}
+
+impl std::fmt::Display for AspmPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub fn parse_aspm_policy(name: &str) -> Option<AspmPolicy> {
+    [
+        AspmPolicy::Default,
+        AspmPolicy::Performance,
+        AspmPolicy::Powersave,
+        AspmPolicy::Powersupersave,
+    ]
+    .into_iter()
+    .find(|policy| policy.name() == name)
+}
+
+/// Reads the active policy, which the kernel brackets in the list of choices.
+/// Returns `None` when the kernel was built without ASPM support.
+pub fn read_aspm_policy(path: &str) -> Result<Option<AspmPolicy>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::new("read", path, err)),
+    };
+
+    let Some(name) = contents
+        .split_whitespace()
+        .find_map(|choice| choice.strip_prefix('[')?.strip_suffix(']'))
+    else {
+        return Err(Error::new("parse", path, "no active policy"));
+    };
+
+    parse_aspm_policy(name)
+        .map(Some)
+        .ok_or_else(|| Error::new("parse", path, format!("unknown policy {}", name)))
+}
+
+pub fn write_aspm_policy(path: &str, policy: AspmPolicy) -> Result<(), Error> {
+    std::fs::write(path, policy.name()).map_err(|err| Error::new("write", path, err))
+}
+
+/// Whether `pcie_aspm=off` on the kernel command line keeps the kernel from managing ASPM.
+pub fn kernel_aspm_disabled(cmdline: &str) -> bool {
+    cmdline
+        .split_whitespace()
+        .filter_map(|parameter| parameter.strip_prefix("pcie_aspm="))
+        .next_back()
+        .is_some_and(|value| value == "off")
+}
+
+/// Link Control and L1 PM Substates Control 1 bits the kernel sets on every link under
+/// `policy`, short of those disabled through the link attributes. `None` for `Default`,
+/// which keeps whatever the firmware chose.
+fn aspm_policy_states(policy: AspmPolicy) -> Option<(u16, u32)> {
+    match policy {
+        AspmPolicy::Default => None,
+        AspmPolicy::Performance => Some((0, 0)),
+        AspmPolicy::Powersave => Some((PCI_EXP_LNKCTL_ASPMC | PCI_EXP_LNKCTL_CLKREQ_EN, 0)),
+        AspmPolicy::Powersupersave => Some((
+            PCI_EXP_LNKCTL_ASPMC | PCI_EXP_LNKCTL_CLKREQ_EN,
+            PCI_L1SS_CTL1_L1SS_MASK,
+        )),
+    }
+}
+
+/// Whether the kernel turns on every state in `link_control_value` and `l1ss_control_value`
+/// once enabled through the link attributes. Those only lift a disable, and `policy`, read
+/// with `read_aspm_policy`, decides what comes on; `Default` brings back the firmware's choice.
+pub fn aspm_policy_enables(
+    policy: Option<AspmPolicy>,
+    link_control_value: u16,
+    l1ss_control_value: u32,
+) -> bool {
+    let Some((policy_link_control_value, policy_l1ss_control_value)) =
+        policy.and_then(aspm_policy_states)
+    else {
+        return false;
+    };
+
+    link_control_value & !policy_link_control_value == 0
+        && l1ss_control_value & !policy_l1ss_control_value == 0
+}
+
+/// Parts of `request` the kernel undoes under `policy` whenever it reconfigures a link.
+/// `Default` keeps whatever the firmware chose, so nothing conflicts with it.
+pub fn aspm_policy_conflicts(policy: AspmPolicy, request: &AspmRequest) -> Vec<String> {
+    let Some((link_control_value, l1ss_control_value)) = aspm_policy_states(policy) else {
+        return Vec::new();
+    };
+
+    let aspm_conflicts = request.mask & (request.flags ^ link_control_value);
+    let l1ss_conflicts = request.l1ss_mask & (request.l1ss_flags ^ l1ss_control_value);
+    let action = |bits_enabled: bool| {
+        if bits_enabled {
+            "enabling"
+        } else {
+            "disabling"
+        }
+    };
+
+    let mut conflicts = Vec::new();
+
+    for bit in [PCI_EXP_LNKCTL_ASPM_L0S, PCI_EXP_LNKCTL_ASPM_L1] {
+        if aspm_conflicts & bit != 0 {
+            conflicts.push(format!(
+                "{} aspm {}",
+                action(request.flags & bit != 0),
+                aspm_control_name(bit)
+            ));
+        }
+    }
+
+    if aspm_conflicts & PCI_EXP_LNKCTL_CLKREQ_EN != 0 {
+        conflicts.push(format!(
+            "{} clock power management",
+            action(request.flags & PCI_EXP_LNKCTL_CLKREQ_EN != 0)
+        ));
+    }
+
+    for (bit, _) in PCI_L1SS_CTL1_NAMES {
+        if l1ss_conflicts & bit != 0 {
+            conflicts.push(format!(
+                "{} {}",
+                action(request.l1ss_flags & bit != 0),
+                l1ss_control_name(bit)
+            ));
+        }
+    }
+
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_aspm_disabled_uses_last_parameter() {
+        assert!(kernel_aspm_disabled("root=/dev/sda1 pcie_aspm=off quiet"));
+        assert!(kernel_aspm_disabled("pcie_aspm=force pcie_aspm=off"));
+        assert!(!kernel_aspm_disabled("pcie_aspm=off pcie_aspm=force"));
+        assert!(!kernel_aspm_disabled("pcie_aspm=force"));
+        assert!(!kernel_aspm_disabled("root=/dev/sda1 xpcie_aspm=off"));
+        assert!(!kernel_aspm_disabled(""));
+    }
+
+    #[test]
+    fn aspm_policy_conflicts_by_policy() {
+        let request = AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPMC,
+            flags: PCI_EXP_LNKCTL_ASPM_L1,
+            l1ss_mask: PCI_L1SS_CTL1_L1SS_MASK,
+            l1ss_flags: PCI_L1SS_CTL1_ASPM_L1_1,
+        };
+
+        assert!(aspm_policy_conflicts(AspmPolicy::Default, &request).is_empty());
+        assert_eq!(
+            aspm_policy_conflicts(AspmPolicy::Performance, &request),
+            ["enabling aspm L1", "enabling ASPM_L1.1"]
+        );
+        assert_eq!(
+            aspm_policy_conflicts(AspmPolicy::Powersave, &request),
+            ["disabling aspm L0s", "enabling ASPM_L1.1"]
+        );
+        assert_eq!(
+            aspm_policy_conflicts(AspmPolicy::Powersupersave, &request),
+            [
+                "disabling aspm L0s",
+                "disabling ASPM_L1.2",
+                "disabling PCI-PM_L1.1",
+                "disabling PCI-PM_L1.2"
+            ]
+        );
+    }
+
+    #[test]
+    fn aspm_policy_conflicts_ignore_unmasked_bits() {
+        let request = AspmRequest {
+            mask: PCI_EXP_LNKCTL_CLKREQ_EN,
+            flags: 0,
+            l1ss_mask: 0,
+            l1ss_flags: PCI_L1SS_CTL1_L1SS_MASK,
+        };
+
+        assert!(aspm_policy_conflicts(AspmPolicy::Performance, &request).is_empty());
+        assert_eq!(
+            aspm_policy_conflicts(AspmPolicy::Powersave, &request),
+            ["disabling clock power management"]
+        );
+    }
+
+    #[test]
+    fn aspm_policy_enables_only_under_powersave() {
+        let l1 = PCI_EXP_LNKCTL_ASPM_L1;
+        let l1_1 = PCI_L1SS_CTL1_ASPM_L1_1;
+
+        for policy in [
+            None,
+            Some(AspmPolicy::Default),
+            Some(AspmPolicy::Performance),
+        ] {
+            assert!(!aspm_policy_enables(policy, l1, 0));
+        }
+
+        assert!(aspm_policy_enables(Some(AspmPolicy::Powersave), l1, 0));
+        assert!(!aspm_policy_enables(Some(AspmPolicy::Powersave), l1, l1_1));
+        assert!(aspm_policy_enables(
+            Some(AspmPolicy::Powersupersave),
+            l1 | PCI_EXP_LNKCTL_CLKREQ_EN,
+            l1_1
+        ));
+    }
+
+    #[test]
+    fn parse_aspm_policy_names() {
+        assert_eq!(
+            parse_aspm_policy("powersupersave"),
+            Some(AspmPolicy::Powersupersave)
+        );
+        assert_eq!(parse_aspm_policy("[default]"), None);
+    }
+}
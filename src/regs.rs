@@ -0,0 +1,155 @@
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_CLASS_REVISION: usize = 0x08;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_HEADER_TYPE_MASK: u8 = 0x7f;
+pub const PCI_HEADER_TYPE_NORMAL: u8 = 0;
+pub const PCI_HEADER_TYPE_BRIDGE: u8 = 1;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_SECONDARY_BUS: usize = 0x19;
+pub const PCI_CFG_SPACE_SIZE: usize = 256;
+pub const PCI_CFG_SPACE_EXP_SIZE: usize = 4096;
+pub const PCI_EXT_CAP_HEADER_LEN: usize = 4;
+pub const PCI_EXT_CAP_ID_L1SS: u16 = 0x1e;
+pub const PCI_EXT_CAP_L1SS_LEN: usize = 0x10;
+pub const PCI_EXT_CAP_ID_LTR: u16 = 0x18;
+pub const PCI_EXT_CAP_LTR_LEN: usize = 0x08;
+pub const PCI_L1SS_CAP: usize = 0x04;
+pub const PCI_L1SS_CAP_PCIPM_L1_2: u32 = 0x00000001;
+pub const PCI_L1SS_CAP_ASPM_L1_2: u32 = 0x00000004;
+pub const PCI_L1SS_CAP_L1_PM_SS: u32 = 0x00000010;
+pub const PCI_L1SS_CAP_CM_RESTORE_TIME: u32 = 0x0000ff00;
+pub const PCI_L1SS_CAP_P_PWR_ON_SCALE: u32 = 0x00030000;
+pub const PCI_L1SS_CAP_P_PWR_ON_VALUE: u32 = 0x00f80000;
+pub const PCI_L1SS_CTL1: usize = 0x08;
+pub const PCI_L1SS_CTL1_PCIPM_L1_2: u32 = 0x00000001;
+pub const PCI_L1SS_CTL1_PCIPM_L1_1: u32 = 0x00000002;
+pub const PCI_L1SS_CTL1_ASPM_L1_2: u32 = 0x00000004;
+pub const PCI_L1SS_CTL1_ASPM_L1_1: u32 = 0x00000008;
+pub const PCI_L1SS_CTL1_L1_2_MASK: u32 = 0x00000005;
+pub const PCI_L1SS_CTL1_L1SS_MASK: u32 = 0x0000000f;
+pub const PCI_L1SS_CTL1_CM_RESTORE_TIME: u32 = 0x0000ff00;
+pub const PCI_L1SS_CTL1_LTR_L12_TH_VALUE: u32 = 0x03ff0000;
+pub const PCI_L1SS_CTL1_LTR_L12_TH_SCALE: u32 = 0xe0000000;
+pub const PCI_L1SS_CTL2: usize = 0x0c;
+pub const PCI_L1SS_CTL2_T_PWR_ON_SCALE: u32 = 0x00000003;
+pub const PCI_L1SS_CTL2_T_PWR_ON_VALUE: u32 = 0x000000f8;
+pub const PCI_LTR_MAX_SNOOP_LAT: usize = 0x04;
+pub const PCI_LTR_MAX_NOSNOOP_LAT: usize = 0x06;
+pub const PCI_LTR_VALUE_MASK: u16 = 0x03ff;
+pub const PCI_LTR_SCALE_MASK: u16 = 0x1c00;
+pub const PCI_CAP_ID_EXP: u8 = 0x10;
+pub const PCI_CAP_ID_EXP_LEN: usize = 0x3c;
+pub const PCI_EXP_FLAGS: usize = 0x02;
+pub const PCI_EXP_FLAGS_VERS: u16 = 0x000f;
+pub const PCI_EXP_FLAGS_TYPE: u16 = 0x00f0;
+pub const PCI_EXP_FLAGS_SLOT: u16 = 0x0100;
+pub const PCI_EXP_FLAGS_IRQ: u16 = 0x3e00;
+pub const PCI_EXP_TYPE_ENDPOINT: u16 = 0x0;
+pub const PCI_EXP_TYPE_LEG_END: u16 = 0x1;
+pub const PCI_EXP_TYPE_ROOT_PORT: u16 = 0x4;
+pub const PCI_EXP_TYPE_UPSTREAM: u16 = 0x5;
+pub const PCI_EXP_TYPE_DOWNSTREAM: u16 = 0x6;
+pub const PCI_EXP_TYPE_PCI_BRIDGE: u16 = 0x7;
+pub const PCI_EXP_TYPE_PCIE_BRIDGE: u16 = 0x8;
+pub const PCI_EXP_TYPE_RC_END: u16 = 0x9;
+pub const PCI_EXP_TYPE_RC_EC: u16 = 0xa;
+pub const PCI_EXP_DEVCAP: usize = 0x04;
+pub const PCI_EXP_DEVCAP_PAYLOAD: u32 = 0x00000007;
+pub const PCI_EXP_DEVCAP_PHANTOM: u32 = 0x00000018;
+pub const PCI_EXP_DEVCAP_EXT_TAG: u32 = 0x00000020;
+pub const PCI_EXP_DEVCAP_L0S: u32 = 0x000001c0;
+pub const PCI_EXP_DEVCAP_L1: u32 = 0x00000e00;
+pub const PCI_EXP_DEVCAP_RBER: u32 = 0x00008000;
+pub const PCI_EXP_DEVCAP_PWR_VAL: u32 = 0x03fc0000;
+pub const PCI_EXP_DEVCAP_PWR_SCL: u32 = 0x0c000000;
+pub const PCI_EXP_DEVCAP_FLR: u32 = 0x10000000;
+pub const PCI_EXP_DEVCTL: usize = 0x08;
+pub const PCI_EXP_DEVCTL_CERE: u16 = 0x0001;
+pub const PCI_EXP_DEVCTL_NFERE: u16 = 0x0002;
+pub const PCI_EXP_DEVCTL_FERE: u16 = 0x0004;
+pub const PCI_EXP_DEVCTL_URRE: u16 = 0x0008;
+pub const PCI_EXP_DEVCTL_RELAX_EN: u16 = 0x0010;
+pub const PCI_EXP_DEVCTL_PAYLOAD: u16 = 0x00e0;
+pub const PCI_EXP_DEVCTL_EXT_TAG: u16 = 0x0100;
+pub const PCI_EXP_DEVCTL_PHANTOM: u16 = 0x0200;
+pub const PCI_EXP_DEVCTL_AUX_PME: u16 = 0x0400;
+pub const PCI_EXP_DEVCTL_NOSNOOP_EN: u16 = 0x0800;
+pub const PCI_EXP_DEVCTL_READRQ: u16 = 0x7000;
+pub const PCI_EXP_DEVCTL_BCR_FLR: u16 = 0x8000;
+pub const PCI_EXP_DEVSTA: usize = 0x0a;
+pub const PCI_EXP_DEVSTA_CED: u16 = 0x0001;
+pub const PCI_EXP_DEVSTA_NFED: u16 = 0x0002;
+pub const PCI_EXP_DEVSTA_FED: u16 = 0x0004;
+pub const PCI_EXP_DEVSTA_URD: u16 = 0x0008;
+pub const PCI_EXP_DEVSTA_AUXPD: u16 = 0x0010;
+pub const PCI_EXP_DEVSTA_TRPND: u16 = 0x0020;
+pub const PCI_EXP_LNKCAP: usize = 0x0c;
+pub const PCI_EXP_LNKCAP_SLS: u32 = 0x0000000f;
+pub const PCI_EXP_LNKCAP_MLW: u32 = 0x000003f0;
+pub const PCI_EXP_LNKCAP_ASPMS: u32 = 0x00000c00;
+pub const PCI_EXP_LNKCAP_L0SEL: u32 = 0x00007000;
+pub const PCI_EXP_LNKCAP_L1EL: u32 = 0x00038000;
+pub const PCI_EXP_LNKCAP_CLKPM: u32 = 0x00040000;
+pub const PCI_EXP_LNKCAP_SDERC: u32 = 0x00080000;
+pub const PCI_EXP_LNKCAP_DLLLARC: u32 = 0x00100000;
+pub const PCI_EXP_LNKCAP_LBNC: u32 = 0x00200000;
+pub const PCI_EXP_LNKCAP_ASPM_COMP: u32 = 0x00400000;
+pub const PCI_EXP_LNKCAP_PN: u32 = 0xff000000;
+pub const PCI_EXP_LNKCTL: usize = 0x10;
+pub const PCI_EXP_LNKCTL_ASPMC: u16 = 0x0003;
+pub const PCI_EXP_LNKCTL_ASPM_L0S: u16 = 0x0001;
+pub const PCI_EXP_LNKCTL_ASPM_L1: u16 = 0x0002;
+pub const PCI_EXP_LNKCTL_RCB: u16 = 0x0008;
+pub const PCI_EXP_LNKCTL_LD: u16 = 0x0010;
+pub const PCI_EXP_LNKCTL_RL: u16 = 0x0020;
+pub const PCI_EXP_LNKCTL_CCC: u16 = 0x0040;
+pub const PCI_EXP_LNKCTL_ES: u16 = 0x0080;
+pub const PCI_EXP_LNKCTL_CLKREQ_EN: u16 = 0x0100;
+pub const PCI_EXP_LNKCTL_HAWD: u16 = 0x0200;
+pub const PCI_EXP_LNKCTL_LBMIE: u16 = 0x0400;
+pub const PCI_EXP_LNKCTL_LABIE: u16 = 0x0800;
+pub const PCI_EXP_LNKSTA: usize = 0x12;
+pub const PCI_EXP_LNKSTA_CLS: u16 = 0x000f;
+pub const PCI_EXP_LNKSTA_NLW: u16 = 0x03f0;
+pub const PCI_EXP_LNKSTA_LT: u16 = 0x0800;
+pub const PCI_EXP_LNKSTA_SLC: u16 = 0x1000;
+pub const PCI_EXP_LNKSTA_DLLLA: u16 = 0x2000;
+pub const PCI_EXP_LNKSTA_LBMS: u16 = 0x4000;
+pub const PCI_EXP_LNKSTA_LABS: u16 = 0x8000;
+pub const PCI_EXP_SLTCAP: usize = 0x14;
+pub const PCI_EXP_SLTCAP_ABP: u32 = 0x00000001;
+pub const PCI_EXP_SLTCAP_PCP: u32 = 0x00000002;
+pub const PCI_EXP_SLTCAP_MRLSP: u32 = 0x00000004;
+pub const PCI_EXP_SLTCAP_AIP: u32 = 0x00000008;
+pub const PCI_EXP_SLTCAP_PIP: u32 = 0x00000010;
+pub const PCI_EXP_SLTCAP_HPS: u32 = 0x00000020;
+pub const PCI_EXP_SLTCAP_HPC: u32 = 0x00000040;
+pub const PCI_EXP_SLTCAP_SPLV: u32 = 0x00007f80;
+pub const PCI_EXP_SLTCAP_SPLS: u32 = 0x00018000;
+pub const PCI_EXP_SLTCAP_EIP: u32 = 0x00020000;
+pub const PCI_EXP_SLTCAP_NCCS: u32 = 0x00040000;
+pub const PCI_EXP_SLTCAP_PSN: u32 = 0xfff80000;
+pub const PCI_EXP_DEVCAP2: usize = 0x24;
+pub const PCI_EXP_DEVCAP2_COMP_TMOUT_RANGES: u32 = 0x0000000f;
+pub const PCI_EXP_DEVCAP2_COMP_TMOUT_DIS: u32 = 0x00000010;
+pub const PCI_EXP_DEVCAP2_ARI: u32 = 0x00000020;
+pub const PCI_EXP_DEVCAP2_ATOMIC_ROUTE: u32 = 0x00000040;
+pub const PCI_EXP_DEVCAP2_ATOMIC_COMP32: u32 = 0x00000080;
+pub const PCI_EXP_DEVCAP2_ATOMIC_COMP64: u32 = 0x00000100;
+pub const PCI_EXP_DEVCAP2_ATOMIC_COMP128: u32 = 0x00000200;
+pub const PCI_EXP_DEVCAP2_LTR: u32 = 0x00000800;
+pub const PCI_EXP_DEVCAP2_OBFF_MASK: u32 = 0x000c0000;
+pub const PCI_EXP_DEVCTL2: usize = 0x28;
+pub const PCI_EXP_DEVCTL2_LTR_EN: u16 = 0x0400;
+pub const PCI_EXP_LNKCAP2: usize = 0x2c;
+pub const PCI_EXP_LNKCAP2_SLS: u32 = 0x000000fe;
+pub const PCI_EXP_LNKCAP2_CROSSLINK: u32 = 0x00000100;
+pub const PCI_EXP_LNKCTL2: usize = 0x30;
+pub const PCI_EXP_LNKCTL2_TLS: u16 = 0x000f;
+pub const PCI_EXP_LNKCTL2_ENTER_COMP: u16 = 0x0010;
+pub const PCI_EXP_LNKCTL2_HASD: u16 = 0x0020;
+pub const PCI_EXP_LNKCTL2_SELECTABLE_DEEMPH: u16 = 0x0040;
+pub const PCI_EXP_LNKCTL2_TX_MARGIN: u16 = 0x0380;
@@ -0,0 +1,96 @@
+use crate::config::PciConfig;
+use crate::dump::LspciDump;
+use crate::error::Error;
+use crate::sysfs::{
+    find_upstream_config_path, list_pci_config_paths, open_pci_config, open_pci_hierarchy,
+    open_pci_link, open_pci_path_to_root, pci_device_name, resolve_config_path,
+};
+
+/// Where configuration space comes from: this machine, or an lspci dump taken elsewhere.
+#[derive(Debug, Default)]
+pub enum ConfigSource {
+    #[default]
+    Sysfs,
+    LspciDump(LspciDump),
+}
+
+impl ConfigSource {
+    /// Whether the configuration space belongs to another machine, so nothing this machine's
+    /// firmware, kernel or sysfs says applies to it.
+    pub fn is_offline(&self) -> bool {
+        matches!(self, ConfigSource::LspciDump(_))
+    }
+
+    pub fn resolve(&self, device: &str) -> Result<String, Error> {
+        match self {
+            ConfigSource::Sysfs => resolve_config_path(device),
+            ConfigSource::LspciDump(dump) => Ok(dump.find(device)?.address.clone()),
+        }
+    }
+
+    pub fn config_paths(&self) -> Result<Vec<String>, Error> {
+        match self {
+            ConfigSource::Sysfs => list_pci_config_paths(),
+            ConfigSource::LspciDump(dump) => Ok(dump.addresses()),
+        }
+    }
+
+    pub fn open(&self, config_path: &str, writable: bool) -> Result<PciConfig, Error> {
+        match self {
+            ConfigSource::Sysfs => open_pci_config(config_path, writable),
+            ConfigSource::LspciDump(dump) => dump.open(config_path),
+        }
+    }
+
+    pub fn find_upstream(&self, config_path: &str) -> Option<String> {
+        match self {
+            ConfigSource::Sysfs => find_upstream_config_path(config_path),
+            ConfigSource::LspciDump(dump) => dump.find_upstream_address(config_path),
+        }
+    }
+
+    /// The form `find_upstream` returns `config_path` in.
+    pub fn canonical_path(&self, config_path: &str) -> Option<String> {
+        match self {
+            ConfigSource::Sysfs => std::fs::canonicalize(config_path)
+                .ok()
+                .map(|path| path.to_string_lossy().into_owned()),
+            ConfigSource::LspciDump(_) => Some(config_path.to_string()),
+        }
+    }
+
+    pub fn device_name(&self, config_path: &str) -> String {
+        match self {
+            ConfigSource::Sysfs => pci_device_name(config_path),
+            ConfigSource::LspciDump(_) => config_path.to_string(),
+        }
+    }
+
+    pub fn open_link(&self, config_path: &str, writable: bool) -> Result<Vec<PciConfig>, Error> {
+        match self {
+            ConfigSource::Sysfs => open_pci_link(config_path, writable),
+            ConfigSource::LspciDump(dump) => dump.open_link(config_path),
+        }
+    }
+
+    pub fn open_path_to_root(
+        &self,
+        config_path: &str,
+    ) -> Result<Vec<(PciConfig, PciConfig)>, Error> {
+        match self {
+            ConfigSource::Sysfs => open_pci_path_to_root(config_path, false),
+            ConfigSource::LspciDump(dump) => dump.open_path_to_root(config_path),
+        }
+    }
+
+    pub fn open_hierarchy(
+        &self,
+        config_path: &str,
+        writable: bool,
+    ) -> Result<Vec<PciConfig>, Error> {
+        match self {
+            ConfigSource::Sysfs => open_pci_hierarchy(config_path, writable),
+            ConfigSource::LspciDump(dump) => dump.open_hierarchy(config_path),
+        }
+    }
+}
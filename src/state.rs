@@ -0,0 +1,479 @@
+use crate::aspm::AspmPlan;
+use crate::capability::{
+    find_pci_exp_capability, find_pci_exp_link_capabilities, find_pci_exp_link_control,
+    find_pci_l1ss, find_pci_ltr,
+};
+use crate::config::{read_config_u16, read_config_u32, PciConfig};
+use crate::error::Error;
+use crate::ltr::{ltr_supported, LtrPlan};
+use crate::regs::*;
+use crate::sysfs::{parse_pci_address, pci_device_name};
+
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/aspmctl/state";
+
+#[derive(Debug, Clone)]
+pub struct SavedState {
+    pub device: String,
+    pub identity: String,
+    pub link_control_value: u16,
+    pub l1ss_control_values: Option<(u32, u32)>,
+    /// Device Control 2, for functions that support LTR.
+    pub device_control2_value: Option<u16>,
+    /// LTR Max Snoop and Max No-Snoop Latency.
+    pub ltr_latency_values: Option<(u16, u16)>,
+}
+
+pub fn pci_device_identity(config_buffer: &[u8]) -> String {
+    let (subsystem_vendor_id, subsystem_id) = match config_buffer
+        .get(PCI_HEADER_TYPE)
+        .map(|header_type| header_type & PCI_HEADER_TYPE_MASK)
+    {
+        Some(PCI_HEADER_TYPE_NORMAL) => (
+            read_config_u16(config_buffer, PCI_SUBSYSTEM_VENDOR_ID),
+            read_config_u16(config_buffer, PCI_SUBSYSTEM_ID),
+        ),
+        _ => (0, 0),
+    };
+
+    format!(
+        "{:04x}:{:04x}:{:04x}:{:04x}:{:08x}",
+        read_config_u16(config_buffer, PCI_VENDOR_ID),
+        read_config_u16(config_buffer, PCI_DEVICE_ID),
+        subsystem_vendor_id,
+        subsystem_id,
+        read_config_u32(config_buffer, PCI_CLASS_REVISION)
+    )
+}
+
+fn parse_saved_state(line: &str) -> Option<SavedState> {
+    let mut fields = line.split_whitespace();
+    let device = parse_pci_address(fields.next()?)?;
+
+    let mut identity = None;
+    let mut link_control_value = None;
+    let mut l1ss_control1_value = None;
+    let mut l1ss_control2_value = None;
+    let mut device_control2_value = None;
+    let mut max_snoop_latency_value = None;
+    let mut max_no_snoop_latency_value = None;
+
+    for field in fields {
+        let (key, value) = field.split_once('=')?;
+        let parse_hex = |value: &str| u32::from_str_radix(value.strip_prefix("0x")?, 16).ok();
+
+        match key {
+            "identity" => identity = Some(value.to_string()),
+            "link_control" => link_control_value = Some(u16::try_from(parse_hex(value)?).ok()?),
+            "l1ss_control1" => l1ss_control1_value = Some(parse_hex(value)?),
+            "l1ss_control2" => l1ss_control2_value = Some(parse_hex(value)?),
+            "device_control2" => {
+                device_control2_value = Some(u16::try_from(parse_hex(value)?).ok()?)
+            }
+            "ltr_max_snoop_latency" => {
+                max_snoop_latency_value = Some(u16::try_from(parse_hex(value)?).ok()?)
+            }
+            "ltr_max_no_snoop_latency" => {
+                max_no_snoop_latency_value = Some(u16::try_from(parse_hex(value)?).ok()?)
+            }
+            _ => return None,
+        }
+    }
+
+    let l1ss_control_values = match (l1ss_control1_value, l1ss_control2_value) {
+        (Some(l1ss_control1_value), Some(l1ss_control2_value)) => {
+            Some((l1ss_control1_value, l1ss_control2_value))
+        }
+        (None, None) => None,
+        _ => return None,
+    };
+
+    let ltr_latency_values = match (max_snoop_latency_value, max_no_snoop_latency_value) {
+        (Some(max_snoop_latency_value), Some(max_no_snoop_latency_value)) => {
+            Some((max_snoop_latency_value, max_no_snoop_latency_value))
+        }
+        (None, None) => None,
+        _ => return None,
+    };
+
+    Some(SavedState {
+        device,
+        identity: identity?,
+        link_control_value: link_control_value?,
+        l1ss_control_values,
+        device_control2_value,
+        ltr_latency_values,
+    })
+}
+
+pub fn read_state_file(path: &str) -> Result<Vec<SavedState>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(value) => value,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::new("read", path, err)),
+    };
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse_saved_state(line).ok_or_else(|| {
+                Error::new(
+                    "error",
+                    path,
+                    format!("line {}: invalid saved state", index + 1),
+                )
+            })
+        })
+        .collect()
+}
+
+pub fn write_state_file(path: &str, states: &[SavedState]) -> Result<(), Error> {
+    if states.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::new("remove", path, err))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    let mut contents =
+        "# aspmctl saved state: device identity and original register values\n".to_string();
+
+    for state in states {
+        contents += &format!(
+            "{} identity={} link_control=0x{:04x}",
+            state.device, state.identity, state.link_control_value
+        );
+
+        if let Some((l1ss_control1_value, l1ss_control2_value)) = state.l1ss_control_values {
+            contents += &format!(
+                " l1ss_control1=0x{:08x} l1ss_control2=0x{:08x}",
+                l1ss_control1_value, l1ss_control2_value
+            );
+        }
+
+        if let Some(device_control2_value) = state.device_control2_value {
+            contents += &format!(" device_control2=0x{:04x}", device_control2_value);
+        }
+
+        if let Some((max_snoop_latency_value, max_no_snoop_latency_value)) =
+            state.ltr_latency_values
+        {
+            contents += &format!(
+                " ltr_max_snoop_latency=0x{:04x} ltr_max_no_snoop_latency=0x{:04x}",
+                max_snoop_latency_value, max_no_snoop_latency_value
+            );
+        }
+
+        contents += "\n";
+    }
+
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| Error::new("create", &parent.to_string_lossy(), err))?;
+        }
+    }
+
+    // Write a temporary file and rename it so an interrupted write never loses saved state.
+    let temporary_path = format!("{}.tmp", path);
+
+    std::fs::write(&temporary_path, contents)
+        .map_err(|err| Error::new("write", &temporary_path, err))?;
+    std::fs::rename(&temporary_path, path).map_err(|err| Error::new("rename", path, err))
+}
+
+/// Records the original values of every register `apply_link` may change in the functions of
+/// `configs`, unless the state file already has them. Returns the paths of the functions left
+/// out because they have no PCI address to restore them by.
+pub fn save_state(state_file: &str, configs: &[PciConfig]) -> Result<Vec<String>, Error> {
+    let mut states = read_state_file(state_file)?;
+    let saved_count = states.len();
+    let mut unsaved_paths = Vec::new();
+
+    for config in configs {
+        let device = pci_device_name(&config.path);
+
+        // Saved state is keyed by address, so a bare config file could never be restored.
+        if parse_pci_address(&device).is_none() {
+            unsaved_paths.push(config.path.clone());
+            continue;
+        }
+
+        // Only the first recorded value is the original one.
+        if states.iter().any(|state| state.device == device) {
+            continue;
+        }
+
+        let link_control_range = find_pci_exp_link_control(&config.buffer)
+            .map_err(|err| err.with_subject(&config.path))?;
+        let l1ss_control_values = find_pci_l1ss(&config.buffer)
+            .map_err(|err| err.with_subject(&config.path))?
+            .map(|l1ss_range| {
+                (
+                    read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL1),
+                    read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL2),
+                )
+            });
+
+        let device_control2_value =
+            if ltr_supported(&config.buffer).map_err(|err| err.with_subject(&config.path))? {
+                let capability_range = find_pci_exp_capability(&config.buffer)
+                    .map_err(|err| err.with_subject(&config.path))?;
+
+                Some(read_config_u16(
+                    &config.buffer,
+                    capability_range.start + PCI_EXP_DEVCTL2,
+                ))
+            } else {
+                None
+            };
+        let ltr_latency_values = find_pci_ltr(&config.buffer)
+            .map_err(|err| err.with_subject(&config.path))?
+            .map(|ltr_range| {
+                (
+                    read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_SNOOP_LAT),
+                    read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_NOSNOOP_LAT),
+                )
+            });
+
+        states.push(SavedState {
+            device,
+            identity: pci_device_identity(&config.buffer),
+            link_control_value: read_config_u16(&config.buffer, link_control_range.start),
+            l1ss_control_values,
+            device_control2_value,
+            ltr_latency_values,
+        });
+    }
+
+    if states.len() > saved_count {
+        write_state_file(state_file, &states)?;
+    }
+
+    Ok(unsaved_paths)
+}
+
+fn check_identity(config: &PciConfig, state: &SavedState) -> Result<(), Error> {
+    let identity = pci_device_identity(&config.buffer);
+
+    if identity != state.identity {
+        return Err(Error::new(
+            "error",
+            &config.path,
+            format!(
+                "device identity {} does not match saved identity {}",
+                identity, state.identity
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn plan_restore(config: &PciConfig, state: &SavedState) -> Result<AspmPlan, Error> {
+    check_identity(config, state)?;
+
+    let link_control_range =
+        find_pci_exp_link_control(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+    let link_capabilities_range = find_pci_exp_link_capabilities(&config.buffer)
+        .map_err(|err| err.with_subject(&config.path))?;
+
+    let mut plan = AspmPlan {
+        link_capabilities_value: read_config_u32(&config.buffer, link_capabilities_range.start),
+        link_control_offset: link_control_range.start,
+        link_control_old_value: read_config_u16(&config.buffer, link_control_range.start),
+        link_control_new_value: state.link_control_value,
+        l1ss_control_offset: None,
+        l1ss_control_old_value: 0,
+        l1ss_control_new_value: 0,
+        l1ss_control2_old_value: 0,
+        l1ss_control2_new_value: 0,
+        l12_timing: None,
+    };
+
+    if let Some((l1ss_control1_value, l1ss_control2_value)) = state.l1ss_control_values {
+        let Some(l1ss_range) =
+            find_pci_l1ss(&config.buffer).map_err(|err| err.with_subject(&config.path))?
+        else {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "unable to find l1 pm substates capability",
+            ));
+        };
+
+        plan.l1ss_control_offset = Some(l1ss_range.start + PCI_L1SS_CTL1);
+        plan.l1ss_control_old_value =
+            read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL1);
+        plan.l1ss_control_new_value = l1ss_control1_value;
+        plan.l1ss_control2_old_value =
+            read_config_u32(&config.buffer, l1ss_range.start + PCI_L1SS_CTL2);
+        plan.l1ss_control2_new_value = l1ss_control2_value;
+    }
+
+    Ok(plan)
+}
+
+/// Plans writing back the LTR settings in `state`, `None` when it has none. Of Device Control 2
+/// only LTR Mechanism Enable comes back; the kernel keeps the other bits up to date.
+pub fn plan_ltr_restore(config: &PciConfig, state: &SavedState) -> Result<Option<LtrPlan>, Error> {
+    if state.device_control2_value.is_none() && state.ltr_latency_values.is_none() {
+        return Ok(None);
+    }
+
+    check_identity(config, state)?;
+
+    let capability_range =
+        find_pci_exp_capability(&config.buffer).map_err(|err| err.with_subject(&config.path))?;
+    let device_control2_offset = capability_range.start + PCI_EXP_DEVCTL2;
+    let device_control2_old_value = match state.device_control2_value {
+        Some(_) => read_config_u16(&config.buffer, device_control2_offset),
+        None => 0,
+    };
+
+    let mut plan = LtrPlan {
+        device_control2_offset,
+        device_control2_old_value,
+        device_control2_new_value: match state.device_control2_value {
+            Some(device_control2_value) => {
+                (device_control2_old_value & !PCI_EXP_DEVCTL2_LTR_EN)
+                    | (device_control2_value & PCI_EXP_DEVCTL2_LTR_EN)
+            }
+            None => device_control2_old_value,
+        },
+        ltr_offset: None,
+        max_snoop_latency_old_value: 0,
+        max_snoop_latency_new_value: 0,
+        max_no_snoop_latency_old_value: 0,
+        max_no_snoop_latency_new_value: 0,
+    };
+
+    if let Some((max_snoop_latency_value, max_no_snoop_latency_value)) = state.ltr_latency_values {
+        let Some(ltr_range) =
+            find_pci_ltr(&config.buffer).map_err(|err| err.with_subject(&config.path))?
+        else {
+            return Err(Error::new(
+                "error",
+                &config.path,
+                "unable to find latency tolerance reporting capability",
+            ));
+        };
+
+        plan.ltr_offset = Some(ltr_range.start);
+        plan.max_snoop_latency_old_value =
+            read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_SNOOP_LAT);
+        plan.max_snoop_latency_new_value = max_snoop_latency_value;
+        plan.max_no_snoop_latency_old_value =
+            read_config_u16(&config.buffer, ltr_range.start + PCI_LTR_MAX_NOSNOOP_LAT);
+        plan.max_no_snoop_latency_new_value = max_no_snoop_latency_value;
+    }
+
+    Ok(Some(plan))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_state_file(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("aspmctl-test-{}", std::process::id()));
+
+        directory.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let path = temporary_state_file("round-trip");
+        let states = vec![
+            SavedState {
+                device: "0000:00:1c.0".to_string(),
+                identity: "8086:a110:0000:0000:060400f0".to_string(),
+                link_control_value: 0x0042,
+                l1ss_control_values: Some((0x4068_2800, 0x0000_0031)),
+                device_control2_value: Some(0x0400),
+                ltr_latency_values: None,
+            },
+            SavedState {
+                device: "0000:03:00.0".to_string(),
+                identity: "144d:a808:144d:a801:01080200".to_string(),
+                link_control_value: 0x0040,
+                l1ss_control_values: None,
+                device_control2_value: Some(0x0000),
+                ltr_latency_values: Some((0x1003, 0x1003)),
+            },
+        ];
+
+        write_state_file(&path, &states).unwrap();
+        let read_states = read_state_file(&path).unwrap();
+        write_state_file(&path, &[]).unwrap();
+
+        assert_eq!(read_states.len(), states.len());
+
+        for (read_state, state) in read_states.iter().zip(&states) {
+            assert_eq!(read_state.device, state.device);
+            assert_eq!(read_state.identity, state.identity);
+            assert_eq!(read_state.link_control_value, state.link_control_value);
+            assert_eq!(read_state.l1ss_control_values, state.l1ss_control_values);
+            assert_eq!(
+                read_state.device_control2_value,
+                state.device_control2_value
+            );
+            assert_eq!(read_state.ltr_latency_values, state.ltr_latency_values);
+        }
+
+        assert!(read_state_file(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn save_state_skips_path_without_address() {
+        let path = temporary_state_file("without-address");
+        let config = PciConfig::new(
+            "ep",
+            Box::new(crate::config::MemoryConfigSpace::new(
+                vec![0; PCI_CFG_SPACE_SIZE],
+            )),
+        )
+        .unwrap();
+
+        assert_eq!(save_state(&path, &[config]).unwrap(), ["ep"]);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[test]
+    fn state_file_reads_lines_without_ltr_values() {
+        let state = parse_saved_state(
+            "0000:03:00.0 identity=144d:a808:144d:a801:01080200 link_control=0x0040",
+        )
+        .unwrap();
+
+        assert_eq!(state.link_control_value, 0x0040);
+        assert_eq!(state.device_control2_value, None);
+        assert_eq!(state.ltr_latency_values, None);
+
+        assert!(parse_saved_state(
+            "0000:03:00.0 identity=144d:a808:144d:a801:01080200 link_control=0x0040 \
+             ltr_max_snoop_latency=0x1003"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn state_file_rejects_invalid_device() {
+        let path = temporary_state_file("invalid-device");
+
+        std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            "ep identity=0000:0000:0000:0000:00000000 link_control=0x0040\n",
+        )
+        .unwrap();
+
+        let result = read_state_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use crate::aspm::AspmPlan;
+use crate::capability::{find_pci_exp_capability, is_pcie_downstream_port};
+use crate::config::{PciConfig, SysfsConfigSpace};
+use crate::error::Error;
+use crate::regs::*;
+
+pub const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Per-link ASPM attributes of the kernel, in enable order, with the Link Control bits behind them.
+pub const SYSFS_LINK_CONTROL_ATTRIBUTES: [(&str, u16); 3] = [
+    ("l0s_aspm", PCI_EXP_LNKCTL_ASPM_L0S),
+    ("l1_aspm", PCI_EXP_LNKCTL_ASPM_L1),
+    ("clkpm", PCI_EXP_LNKCTL_CLKREQ_EN),
+];
+
+/// Per-link L1 PM Substates attributes of the kernel, in enable order.
+pub const SYSFS_LINK_L1SS_ATTRIBUTES: [(&str, u32); 4] = [
+    ("l1_1_aspm", PCI_L1SS_CTL1_ASPM_L1_1),
+    ("l1_2_aspm", PCI_L1SS_CTL1_ASPM_L1_2),
+    ("l1_1_pcipm", PCI_L1SS_CTL1_PCIPM_L1_1),
+    ("l1_2_pcipm", PCI_L1SS_CTL1_PCIPM_L1_2),
+];
+
+pub fn parse_pci_address(address: &str) -> Option<String> {
+    let (domain, bus_device_function) = match address.matches(':').count() {
+        1 => ("0000", address),
+        2 => address.split_once(':')?,
+        _ => return None,
+    };
+
+    let (bus, device_function) = bus_device_function.split_once(':')?;
+    let (device, function) = device_function.split_once('.')?;
+
+    let is_hex = |field: &str, max_length: usize| {
+        !field.is_empty()
+            && field.len() <= max_length
+            && field.chars().all(|c| c.is_ascii_hexdigit())
+    };
+
+    if !is_hex(domain, 4) || !is_hex(bus, 2) || !is_hex(device, 2) || !is_hex(function, 1) {
+        return None;
+    }
+
+    let domain = u16::from_str_radix(domain, 16).ok()?;
+    let bus = u8::from_str_radix(bus, 16).ok()?;
+    let device = u8::from_str_radix(device, 16).ok()?;
+    let function = u8::from_str_radix(function, 16).ok()?;
+
+    if device > 0x1f || function > 7 {
+        return None;
+    }
+
+    Some(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        domain, bus, device, function
+    ))
+}
+
+pub fn resolve_config_path(device: &str) -> Result<String, Error> {
+    let device_path = std::path::Path::new(device);
+
+    if device_path.is_dir() {
+        let config_path = device_path.join("config");
+
+        if !config_path.is_file() {
+            return Err(Error::new("error", device, "not a pci device directory"));
+        }
+
+        return Ok(config_path.to_string_lossy().into_owned());
+    }
+
+    if device_path.exists() || device.contains('/') {
+        return Ok(device.to_string());
+    }
+
+    let Some(address) = parse_pci_address(device) else {
+        return Err(Error::new("syntax", device, "invalid pci address"));
+    };
+
+    let config_path = std::path::Path::new(SYSFS_PCI_DEVICES)
+        .join(&address)
+        .join("config");
+
+    if !config_path.is_file() {
+        return Err(Error::new("error", &address, "no such pci device"));
+    }
+
+    Ok(config_path.to_string_lossy().into_owned())
+}
+
+pub fn pci_device_name(config_path: &str) -> String {
+    std::fs::canonicalize(config_path)
+        .ok()
+        .and_then(|path| Some(path.parent()?.file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| config_path.to_string())
+}
+
+pub fn list_pci_config_paths() -> Result<Vec<String>, Error> {
+    let entries = std::fs::read_dir(SYSFS_PCI_DEVICES)
+        .map_err(|err| Error::new("read", SYSFS_PCI_DEVICES, err))?;
+
+    let mut config_paths: Vec<String> = entries
+        .filter_map(|entry| Some(entry.ok()?.path().join("config")))
+        .filter(|config_path| config_path.is_file())
+        .map(|config_path| config_path.to_string_lossy().into_owned())
+        .collect();
+
+    config_paths.sort();
+
+    Ok(config_paths)
+}
+
+pub fn find_upstream_config_path(path: &str) -> Option<String> {
+    let config_path = std::fs::canonicalize(path).ok()?;
+    let upstream_config_path = config_path.parent()?.parent()?.join("config");
+
+    if !upstream_config_path.is_file() {
+        return None;
+    }
+
+    Some(upstream_config_path.to_string_lossy().into_owned())
+}
+
+pub fn open_pci_config(path: &str, writable: bool) -> Result<PciConfig, Error> {
+    PciConfig::new(path, Box::new(SysfsConfigSpace::open(path, writable)?))
+}
+
+pub fn open_pci_link(config_path: &str, writable: bool) -> Result<Vec<PciConfig>, Error> {
+    let mut link = Vec::new();
+
+    if let Some(upstream_path) = find_upstream_config_path(config_path) {
+        let upstream_config = open_pci_config(&upstream_path, writable)?;
+
+        if is_pcie_downstream_port(&upstream_config.buffer) {
+            link.push(upstream_config);
+        }
+    }
+
+    link.push(open_pci_config(config_path, writable)?);
+
+    Ok(link)
+}
+
+/// Opens every link between `config_path` and its root port, nearest link first,
+/// as (downstream port, component below it) pairs.
+pub fn open_pci_path_to_root(
+    config_path: &str,
+    writable: bool,
+) -> Result<Vec<(PciConfig, PciConfig)>, Error> {
+    let mut links = Vec::new();
+    let mut downstream_path = config_path.to_string();
+
+    while let Some(upstream_path) = find_upstream_config_path(&downstream_path) {
+        let upstream_config = open_pci_config(&upstream_path, writable)?;
+
+        if !is_pcie_downstream_port(&upstream_config.buffer) {
+            break;
+        }
+
+        links.push((
+            upstream_config,
+            open_pci_config(&downstream_path, writable)?,
+        ));
+
+        // The port above a switch downstream port is the switch's own upstream port.
+        let Some(switch_upstream_path) = find_upstream_config_path(&upstream_path) else {
+            break;
+        };
+
+        downstream_path = switch_upstream_path;
+    }
+
+    Ok(links)
+}
+
+/// Opens every function from the root port down to `config_path`, which comes last.
+pub fn open_pci_hierarchy(config_path: &str, writable: bool) -> Result<Vec<PciConfig>, Error> {
+    let mut hierarchy = vec![open_pci_config(config_path, writable)?];
+    let mut path = config_path.to_string();
+
+    while let Some(upstream_path) = find_upstream_config_path(&path) {
+        let upstream_config = open_pci_config(&upstream_path, writable)?;
+
+        if find_pci_exp_capability(&upstream_config.buffer).is_err() {
+            break;
+        }
+
+        hierarchy.push(upstream_config);
+        path = upstream_path;
+    }
+
+    hierarchy.reverse();
+
+    Ok(hierarchy)
+}
+
+/// The kernel's ASPM controls for the link above `config_path`, if the kernel exposes them.
+pub fn sysfs_link_path(config_path: &str) -> Option<String> {
+    let link_path = std::path::Path::new(config_path).parent()?.join("link");
+
+    if !link_path.is_dir() {
+        return None;
+    }
+
+    Some(link_path.to_string_lossy().into_owned())
+}
+
+/// Attribute writes that bring the kernel's view of the link to the new values in `plans`,
+/// disables first, or `None` when the plans change anything the attributes do not cover.
+pub fn plan_sysfs_link(
+    link_path: &str,
+    link: &[PciConfig],
+    plans: &[AspmPlan],
+) -> Result<Option<Vec<(String, bool)>>, Error> {
+    let Some(target_plan) = plans.last() else {
+        return Ok(Some(Vec::new()));
+    };
+
+    let link_control_bits = PCI_EXP_LNKCTL_ASPMC | PCI_EXP_LNKCTL_CLKREQ_EN;
+
+    for (config, plan) in link.iter().zip(plans) {
+        if (plan.link_control_old_value ^ plan.link_control_new_value) & !link_control_bits != 0
+            || (plan.l1ss_control_old_value ^ plan.l1ss_control_new_value)
+                & !PCI_L1SS_CTL1_L1SS_MASK
+                != 0
+            || plan.l1ss_control2_old_value != plan.l1ss_control2_new_value
+            || plan.l12_timing.is_some()
+        {
+            return Ok(None);
+        }
+
+        // The attributes describe the whole link, so every function has to agree.
+        let compared_bits = if is_pcie_downstream_port(&config.buffer) {
+            PCI_EXP_LNKCTL_ASPMC
+        } else {
+            link_control_bits
+        };
+
+        if (plan.link_control_new_value ^ target_plan.link_control_new_value) & compared_bits != 0
+            || (plan.l1ss_control_offset.is_some()
+                && (plan.l1ss_control_new_value ^ target_plan.l1ss_control_new_value)
+                    & PCI_L1SS_CTL1_L1SS_MASK
+                    != 0)
+        {
+            return Ok(None);
+        }
+    }
+
+    let mut attributes: Vec<(&str, bool)> = SYSFS_LINK_CONTROL_ATTRIBUTES
+        .iter()
+        .map(|(name, bit)| (*name, target_plan.link_control_new_value & bit != 0))
+        .collect();
+
+    if target_plan.l1ss_control_offset.is_some() {
+        attributes.extend(
+            SYSFS_LINK_L1SS_ATTRIBUTES
+                .iter()
+                .map(|(name, bit)| (*name, target_plan.l1ss_control_new_value & bit != 0)),
+        );
+    }
+
+    let mut disables = Vec::new();
+    let mut enables = Vec::new();
+
+    for (name, enable) in attributes {
+        let attribute_path = std::path::Path::new(link_path).join(name);
+
+        let current = match std::fs::read_to_string(&attribute_path) {
+            Ok(value) => value.trim() == "1",
+            // The kernel hides the attributes of states the link does not support.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if enable {
+                    return Ok(None);
+                }
+
+                continue;
+            }
+            Err(err) => return Err(Error::new("read", &attribute_path.to_string_lossy(), err)),
+        };
+
+        if current != enable {
+            let write = (attribute_path.to_string_lossy().into_owned(), enable);
+
+            if enable {
+                enables.push(write);
+            } else {
+                disables.push(write);
+            }
+        }
+    }
+
+    disables.reverse();
+    disables.extend(enables);
+
+    Ok(Some(disables))
+}
+
+pub fn write_sysfs_link(writes: &[(String, bool)]) -> Result<(), Error> {
+    for (attribute_path, enable) in writes {
+        std::fs::write(attribute_path, if *enable { "1" } else { "0" })
+            .map_err(|err| Error::new("write", attribute_path, err))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aspm::{plan_aspm, AspmRequest};
+    use crate::config::testing::*;
+
+    /// A link attribute directory holding `attributes` with their current values.
+    fn link_directory(name: &str, attributes: &[(&str, bool)]) -> String {
+        let directory = std::env::temp_dir()
+            .join(format!("aspmctl-test-{}", std::process::id()))
+            .join(name);
+
+        std::fs::create_dir_all(&directory).unwrap();
+
+        for (attribute, enabled) in attributes {
+            std::fs::write(
+                directory.join(attribute),
+                if *enabled { "1\n" } else { "0\n" },
+            )
+            .unwrap();
+        }
+
+        directory.to_string_lossy().into_owned()
+    }
+
+    fn link_plans(request: &AspmRequest) -> (Vec<PciConfig>, Vec<AspmPlan>) {
+        let log = WriteLog::default();
+        let link = vec![
+            TestFunction::new(PCI_EXP_TYPE_ROOT_PORT).open("rp", &log),
+            TestFunction::new(PCI_EXP_TYPE_ENDPOINT).open("ep", &log),
+        ];
+        let plans = link
+            .iter()
+            .map(|config| plan_aspm(request, config).unwrap())
+            .collect();
+
+        (link, plans)
+    }
+
+    #[test]
+    fn plan_sysfs_link_disables_before_enabling() {
+        let link_path = link_directory(
+            "disables-first",
+            &[("l0s_aspm", false), ("l1_aspm", true), ("clkpm", true)],
+        );
+        let (link, plans) = link_plans(&AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPMC,
+            flags: PCI_EXP_LNKCTL_ASPM_L0S,
+            ..AspmRequest::default()
+        });
+        let attribute = |name: &str| format!("{}/{}", link_path, name);
+
+        // Clock PM is not in the Link Control of either test function, so it goes as well.
+        assert_eq!(
+            plan_sysfs_link(&link_path, &link, &plans).unwrap(),
+            Some(vec![
+                (attribute("clkpm"), false),
+                (attribute("l1_aspm"), false),
+                (attribute("l0s_aspm"), true),
+            ])
+        );
+    }
+
+    #[test]
+    fn plan_sysfs_link_needs_attributes_for_enables() {
+        let link_path = link_directory("missing", &[("l1_aspm", false)]);
+        let (link, plans) = link_plans(&AspmRequest::default());
+
+        // The kernel hides attributes of unsupported states, which then stay off anyway.
+        assert_eq!(
+            plan_sysfs_link(&link_path, &link, &plans).unwrap(),
+            Some(Vec::new())
+        );
+
+        let (link, plans) = link_plans(&AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPM_L0S,
+            flags: PCI_EXP_LNKCTL_ASPM_L0S,
+            ..AspmRequest::default()
+        });
+
+        assert_eq!(plan_sysfs_link(&link_path, &link, &plans).unwrap(), None);
+    }
+
+    #[test]
+    fn plan_sysfs_link_refuses_what_attributes_cannot_express() {
+        let link_path = link_directory("refused", &[("l1_aspm", false)]);
+        let request = AspmRequest {
+            mask: PCI_EXP_LNKCTL_ASPM_L1,
+            flags: PCI_EXP_LNKCTL_ASPM_L1,
+            ..AspmRequest::default()
+        };
+
+        // Both ends have to agree.
+        let (link, mut plans) = link_plans(&request);
+
+        plans[0].link_control_new_value = 0;
+
+        assert_eq!(plan_sysfs_link(&link_path, &link, &plans).unwrap(), None);
+
+        // Bits other than ASPM and Clock PM have no attribute.
+        let (link, mut plans) = link_plans(&request);
+
+        for plan in &mut plans {
+            plan.link_control_new_value |= PCI_EXP_LNKCTL_CCC;
+        }
+
+        assert_eq!(plan_sysfs_link(&link_path, &link, &plans).unwrap(), None);
+
+        // A downstream port has no say in Clock PM.
+        let (link, mut plans) = link_plans(&request);
+
+        plans[0].link_control_new_value |= PCI_EXP_LNKCTL_CLKREQ_EN;
+
+        assert_eq!(
+            plan_sysfs_link(&link_path, &link, &plans).unwrap(),
+            Some(vec![(format!("{}/l1_aspm", link_path), true)])
+        );
+    }
+
+    #[test]
+    fn parse_pci_address_adds_domain_and_normalizes() {
+        assert_eq!(
+            parse_pci_address("03:00.0").as_deref(),
+            Some("0000:03:00.0")
+        );
+        assert_eq!(parse_pci_address("1:2.3").as_deref(), Some("0000:01:02.3"));
+        assert_eq!(
+            parse_pci_address("0001:3A:1F.7").as_deref(),
+            Some("0001:3a:1f.7")
+        );
+    }
+
+    #[test]
+    fn parse_pci_address_rejects_invalid_addresses() {
+        for address in [
+            "",
+            "03:00",
+            "0000:03:00",
+            "03.00.0",
+            "0:0:0:0.0",
+            "00000:00:00.0",
+            "000:100:00.0",
+            "00:20.0",
+            "00:00.8",
+            "00:00.10",
+            "zz:00.0",
+            "+0:00.0",
+            "00:00.",
+        ] {
+            assert_eq!(parse_pci_address(address), None, "{}", address);
+        }
+    }
+}